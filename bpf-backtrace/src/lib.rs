@@ -7,6 +7,7 @@ pub struct UnwindMap {
     pc: Vec<u64>,
     rip: Vec<Instruction>,
     rsp: Vec<Instruction>,
+    rbp: Vec<Instruction>,
 }
 
 impl UnwindMap {
@@ -15,6 +16,7 @@ impl UnwindMap {
         let mut pc = vec![];
         let mut rip = vec![];
         let mut rsp = vec![];
+        let mut rbp = vec![];
         for entry in map.iter() {
             let elf = Elf::open(&entry.path)?;
             let table = elf.unwind_table()?;
@@ -23,9 +25,10 @@ impl UnwindMap {
                 pc.push(addr as u64);
                 rip.push(row.rip.into());
                 rsp.push(row.rsp.into());
+                rbp.push(row.rbp.into());
             }
         }
-        Ok(Self { pc, rip, rsp })
+        Ok(Self { pc, rip, rsp, rbp })
     }
 
     pub fn binary_search(&self, ip: u64) -> usize {
@@ -52,6 +55,7 @@ pub struct UnwindContext {
     map: UnwindMap,
     rip: u64,
     rsp: u64,
+    rbp: u64,
 }
 
 impl UnwindContext {
@@ -69,6 +73,7 @@ impl UnwindContext {
             map,
             rip: ctx.uc_mcontext.gregs[libc::REG_RIP as usize] as u64,
             rsp: ctx.uc_mcontext.gregs[libc::REG_RSP as usize] as u64,
+            rbp: ctx.uc_mcontext.gregs[libc::REG_RBP as usize] as u64,
         })
     }
 
@@ -83,12 +88,15 @@ impl UnwindContext {
         let i = self.map.binary_search(self.rip);
         let irip = self.map.rip[i];
         let irsp = self.map.rsp[i];
+        let irbp = self.map.rbp[i];
 
-        let cfa = execute_instruction(&irsp, self.rip, self.rsp, 0).unwrap();
-        let rip = execute_instruction(&irip, self.rip, self.rsp, cfa).unwrap_or_default();
+        let cfa = execute_instruction(&irsp, self, 0).unwrap();
+        let rip = execute_instruction(&irip, self, cfa).unwrap_or_default();
+        let rbp = execute_instruction(&irbp, self, cfa).unwrap_or_default();
 
         self.rip = rip;
         self.rsp = cfa;
+        self.rbp = rbp;
 
         true
     }
@@ -100,15 +108,28 @@ impl UnwindContext {
     pub fn rsp(&self) -> u64 {
         self.rsp
     }
+
+    pub fn rbp(&self) -> u64 {
+        self.rbp
+    }
 }
 
-fn execute_instruction(ins: &Instruction, rip: u64, rsp: u64, cfa: u64) -> Option<u64> {
+fn execute_instruction(ins: &Instruction, ctx: &UnwindContext, cfa: u64) -> Option<u64> {
     match (ins.op(), ins.reg(), ins.offset()) {
         (Op::CfaOffset, None, Some(offset)) => {
             Some(unsafe { *((cfa as i64 + offset) as *const u64) })
         }
-        (Op::Register, Some(Reg::Rip), Some(offset)) => Some((rip as i64 + offset) as u64),
-        (Op::Register, Some(Reg::Rsp), Some(offset)) => Some((rsp as i64 + offset) as u64),
+        (Op::Register, Some(Reg::Rip), Some(offset)) => Some((ctx.rip as i64 + offset) as u64),
+        (Op::Register, Some(Reg::Rsp), Some(offset)) => Some((ctx.rsp as i64 + offset) as u64),
+        (Op::Register, Some(Reg::Rbp), Some(offset)) => Some((ctx.rbp as i64 + offset) as u64),
+        (Op::Plt, Some(Reg::Rsp), Some(offset)) => {
+            let pushed = if ctx.rip & 15 >= ins.arg()? as u64 {
+                8
+            } else {
+                0
+            };
+            Some((ctx.rsp as i64 + offset + pushed) as u64)
+        }
         _ => None,
     }
 }
@@ -51,6 +51,23 @@ impl<K, V, const T: u32> RawMap<K, V, T> {
         );
     }
 
+    /// Set the `value` in the map for `key`, returns the error of the helper when the
+    /// map is full.
+    #[inline(always)]
+    pub unsafe fn try_update(&self, key: &K, value: &V) -> Result<(), c_int> {
+        let ret = bpf_helpers_sys::bpf_map_update_elem(
+            &self.def as *const _ as *mut c_void,
+            key as *const _ as *const c_void,
+            value as *const _ as *const c_void,
+            bpf_helpers_sys::BPF_ANY.into(),
+        );
+        if ret >= 0 {
+            Ok(())
+        } else {
+            Err(ret)
+        }
+    }
+
     /// Delete the entry indexed by `key`
     #[inline(always)]
     pub unsafe fn delete(&self, key: &K) {
@@ -88,6 +105,12 @@ macro_rules! impl_hash_map {
                 unsafe { self.update(key, value) }
             }
 
+            /// Inserts the `value` in the map for `key`, fails when the map is full.
+            #[inline(always)]
+            pub fn try_insert(&self, key: &K, value: &V) -> Result<(), c_int> {
+                unsafe { self.try_update(key, value) }
+            }
+
             /// Removes the entry indexed by `key`
             #[inline(always)]
             pub fn remove(&self, key: &K) {
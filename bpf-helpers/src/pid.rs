@@ -6,11 +6,15 @@ impl PidTgid {
         Self(unsafe { bpf_helpers_sys::bpf_get_current_pid_tgid() })
     }
 
+    /// Id of the process, the same for all threads of a process. The kernel calls it
+    /// the thread group id.
     pub fn pid(&self) -> u32 {
         (self.0 >> 32) as _
     }
 
-    pub fn tgid(&self) -> u32 {
-        (self.0 & 0xf) as _
+    /// Id of the thread, equal to the process id for the main thread. The kernel calls
+    /// it the pid.
+    pub fn tid(&self) -> u32 {
+        (self.0 & 0xffff_ffff) as _
     }
 }
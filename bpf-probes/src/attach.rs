@@ -107,10 +107,13 @@ impl AttachedProbe {
                 attr.__bindgen_anon_1 = sys::perf_event_attr__bindgen_ty_1 { sample_freq: *f };
             }
         }
+        if let Some(pid) = pid {
+            return Self::open_for_process(&attr, pid);
+        }
         Self::open_for_every_cpu(&attr, pid)
     }
 
-    pub fn interval(interval: &Interval, pid: Option<u32>) -> Result<Self> {
+    pub fn interval(interval: &Interval, pid: Option<u32>) -> Result<Vec<Self>> {
         let mut attr: perf_event_attr = unsafe { std::mem::zeroed() };
         attr.size = std::mem::size_of::<perf_event_attr>() as _;
         attr.type_ = sys::perf_type_id_PERF_TYPE_SOFTWARE;
@@ -136,10 +139,13 @@ impl AttachedProbe {
                 attr.__bindgen_anon_1 = sys::perf_event_attr__bindgen_ty_1 { sample_freq: *f };
             }
         }
-        Self::open_for_any_cpu(&attr, pid)
+        if let Some(pid) = pid {
+            return Self::open_for_process(&attr, pid);
+        }
+        Ok(vec![Self::open_for_any_cpu(&attr, pid)?])
     }
 
-    pub fn software(event: SoftwareEvent, count: u64, pid: Option<u32>) -> Result<Self> {
+    pub fn software(event: SoftwareEvent, count: u64, pid: Option<u32>) -> Result<Vec<Self>> {
         use SoftwareEvent::*;
         let mut attr: perf_event_attr = unsafe { std::mem::zeroed() };
         attr.size = std::mem::size_of::<perf_event_attr>() as _;
@@ -160,7 +166,10 @@ impl AttachedProbe {
         attr.__bindgen_anon_1 = sys::perf_event_attr__bindgen_ty_1 {
             sample_period: count,
         };
-        Self::open_for_any_cpu(&attr, pid)
+        if let Some(pid) = pid {
+            return Self::open_for_process(&attr, pid);
+        }
+        Ok(vec![Self::open_for_any_cpu(&attr, pid)?])
     }
 
     pub fn hardware(event: HardwareEvent, count: u64, pid: Option<u32>) -> Result<Vec<Self>> {
@@ -183,6 +192,9 @@ impl AttachedProbe {
         attr.__bindgen_anon_1 = sys::perf_event_attr__bindgen_ty_1 {
             sample_period: count,
         };
+        if let Some(pid) = pid {
+            return Self::open_for_process(&attr, pid);
+        }
         Self::open_for_every_cpu(&attr, pid)
     }
 
@@ -210,6 +222,19 @@ impl AttachedProbe {
             .collect()
     }
 
+    /// Opens the event for all threads of the process `pid` on any cpu.
+    ///
+    /// An event opened for a thread only samples that thread, so every existing thread
+    /// gets it's own event. Threads spawned afterwards inherit the event of their parent.
+    fn open_for_process(attr: &perf_event_attr, pid: u32) -> Result<Vec<Self>> {
+        let mut attr = *attr;
+        attr.set_inherit(1);
+        bpf_utils::task::threads(pid)?
+            .into_iter()
+            .map(|tid| Self::open_for_cpu(&attr, Some(tid), -1))
+            .collect()
+    }
+
     fn open_for_any_cpu(attr: &perf_event_attr, pid: Option<u32>) -> Result<Self> {
         Self::open_for_cpu(attr, pid, 0)
     }
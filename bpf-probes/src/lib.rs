@@ -371,10 +371,10 @@ impl Probe {
                 vec![AttachedProbe::tracepoint(category, name, pid)?]
             }
             Self::Profile { interval } => AttachedProbe::profile(interval, pid)?,
-            Self::Interval { interval } => vec![AttachedProbe::interval(interval, pid)?],
+            Self::Interval { interval } => AttachedProbe::interval(interval, pid)?,
             Self::Software { event, count } => {
                 let count = count.unwrap_or_else(|| event.default_count());
-                vec![AttachedProbe::software(*event, count, pid)?]
+                AttachedProbe::software(*event, count, pid)?
             }
             Self::Hardware { event, count } => {
                 let count = count.unwrap_or_else(|| event.default_count());
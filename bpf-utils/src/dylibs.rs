@@ -1,10 +1,10 @@
 use crate::elf::{BuildId, Dwarf, Elf};
-use crate::maps::AddressMap;
+use crate::maps::{AddressEntry, AddressMap};
+use crate::ptrace::Ptracer;
 use addr2line::Location;
 use anyhow::Result;
-use cargo_subcommand::{CrateType, Subcommand};
-use ptracer::{ContinueMode, Ptracer};
 use std::path::Path;
+use std::process::{Child, Command};
 
 pub struct Binary {
     pub start_addr: usize,
@@ -13,50 +13,118 @@ pub struct Binary {
     pub dwarf: Option<Dwarf>,
 }
 
+/// Function at an address, with the location of the address in the function.
+pub struct ResolvedFrame<'a> {
+    pub name: String,
+    pub location: Option<Location<'a>>,
+    /// Set when the function was inlined into the next frame.
+    pub inlined: bool,
+}
+
 pub struct BinaryInfo {
     map: Vec<Binary>,
-    ptracer: Ptracer,
+    /// Binaries unloaded with `dlclose`, kept to symbolize the samples taken while they
+    /// were loaded.
+    unloaded: Vec<Binary>,
+    pid: u32,
+    ptracer: Option<Ptracer>,
+    child: Option<Child>,
 }
 
 impl BinaryInfo {
-    pub fn from_cargo_subcommand(cmd: &Subcommand) -> Result<Self> {
-        log::debug!("{:?}", cmd);
-        let artifact = &cmd.artifacts()[0];
-        let path = cmd
-            .target_dir()
-            .join(cmd.target().unwrap_or(""))
-            .join(cmd.profile())
-            .join(artifact.as_ref())
-            .join(artifact.file_name(CrateType::Bin, cmd.target().unwrap_or("")));
-        Self::new(&path, &[])
+    pub fn new(path: &Path, args: &[String]) -> Result<Self> {
+        let mut cmd = Command::new(path);
+        cmd.args(args);
+        Self::spawn(cmd)
     }
 
-    pub fn new(path: &Path, args: &[String]) -> Result<Self> {
-        log::debug!("loading {}", path.display());
-        let mut ptracer = Ptracer::spawn(&path, args)?;
-        log::debug!("loaded program with pid {}", ptracer.pid());
-        let address_map = AddressMap::load_pid(i32::from(ptracer.pid()) as u32)?;
+    /// Spawns the program and stops it at `_start`, after all dynamic libraries
+    /// have been loaded.
+    pub fn spawn(cmd: Command) -> Result<Self> {
+        log::debug!("loading {:?}", cmd);
+        let mut ptracer = Ptracer::spawn(cmd)?;
+        let pid = ptracer.pid();
+        log::debug!("loaded program with pid {}", pid);
+        let address_map = AddressMap::load_pid(pid)?;
         let load_addr = address_map[0].start_addr;
         let offset = Elf::open(&address_map[0].path)?
             .resolve_symbol("_start", 0)?
             .unwrap();
-        ptracer.insert_breakpoint(load_addr + offset)?;
-        ptracer.enable_breakpoint(load_addr + offset)?;
-        ptracer.cont(ContinueMode::Default)?;
-        ptracer.remove_breakpoint(load_addr + offset)?;
-        let address_map = AddressMap::load_pid(i32::from(ptracer.pid()) as u32)?;
-        let mut map = vec![];
-        for entry in address_map.iter() {
-            let elf = Elf::open(&entry.path)?;
-            let dwarf = elf.dwarf().ok();
-            map.push(Binary {
-                start_addr: entry.start_addr,
-                end_addr: entry.end_addr,
-                elf,
-                dwarf,
-            });
+        ptracer.run_until(load_addr + offset)?;
+        Ok(Self {
+            map: load_binaries(pid)?,
+            unloaded: vec![],
+            pid,
+            ptracer: Some(ptracer),
+            child: None,
+        })
+    }
+
+    /// Loads the binaries of an already running process without stopping it.
+    pub fn from_pid(pid: u32) -> Result<Self> {
+        log::debug!("loading binaries of running process {}", pid);
+        Ok(Self {
+            map: load_binaries(pid)?,
+            unloaded: vec![],
+            pid,
+            ptracer: None,
+            child: None,
+        })
+    }
+
+    /// Creates a `BinaryInfo` from binaries of a process that was traced earlier,
+    /// used to symbolize recorded profiles.
+    pub fn from_binaries(pid: u32, mut map: Vec<Binary>) -> Self {
+        map.sort_by_key(|binary| binary.start_addr);
+        Self {
+            map,
+            unloaded: vec![],
+            pid,
+            ptracer: None,
+            child: None,
         }
-        Ok(Self { map, ptracer })
+    }
+
+    /// Adds binaries that were unloaded before the profile was recorded.
+    pub fn with_unloaded(mut self, unloaded: Vec<Binary>) -> Self {
+        self.unloaded = unloaded;
+        self
+    }
+
+    /// Reloads the address map after libraries were loaded with `dlopen` or unloaded
+    /// with `dlclose`, returns `true` if the binaries changed.
+    ///
+    /// Binaries that are still mapped at the same address aren't loaded again, unloaded
+    /// binaries are kept to symbolize the samples taken before they were unloaded.
+    pub fn reload(&mut self) -> Result<bool> {
+        let address_map = AddressMap::load_pid(self.pid)?;
+        let entries: Vec<_> = self
+            .map
+            .iter()
+            .map(|binary| AddressEntry {
+                path: binary.elf.path().to_owned(),
+                start_addr: binary.start_addr,
+                end_addr: binary.end_addr,
+            })
+            .collect();
+        let (added, removed) = diff(&entries, &address_map);
+        if added.is_empty() && removed.is_empty() {
+            return Ok(false);
+        }
+        let mut loaded = vec![];
+        for i in added {
+            let entry = &address_map[i];
+            log::debug!("loading {} into {}", entry.path.display(), self.pid);
+            loaded.push(load_binary(entry)?);
+        }
+        for i in removed.into_iter().rev() {
+            let binary = self.map.remove(i);
+            log::debug!("unloaded {} from {}", binary.elf.path().display(), self.pid);
+            self.unloaded.push(binary);
+        }
+        self.map.extend(loaded);
+        self.map.sort_by_key(|binary| binary.start_addr);
+        Ok(true)
     }
 
     pub fn path(&self) -> &Path {
@@ -75,31 +143,90 @@ impl BinaryInfo {
         self.map[0].dwarf.as_ref()
     }
 
-    pub fn ptracer(&self) -> &Ptracer {
-        &self.ptracer
+    pub fn ptracer(&self) -> Option<&Ptracer> {
+        self.ptracer.as_ref()
     }
 
     pub fn pid(&self) -> u32 {
-        i32::from(self.ptracer.pid()) as _
+        self.pid
     }
 
+    /// Resumes the program if it was spawned by us.
     pub fn cont(&mut self) -> Result<()> {
-        self.ptracer.cont(ContinueMode::Default)?;
+        if let Some(ptracer) = self.ptracer.take() {
+            self.child = Some(ptracer.detach()?);
+        }
+        Ok(())
+    }
+
+    /// Returns `true` if the program exited.
+    ///
+    /// Processes that were not spawned by us can't be waited on, so we check if
+    /// the process is still listed in procfs.
+    pub fn has_exited(&mut self) -> Result<bool> {
+        if let Some(child) = self.child.as_mut() {
+            Ok(child.try_wait()?.is_some())
+        } else {
+            Ok(!Path::new(&format!("/proc/{}", self.pid)).exists())
+        }
+    }
+
+    /// Kills the program.
+    pub fn kill(&mut self) -> Result<()> {
+        if let Some(child) = self.child.as_mut() {
+            child.kill()?;
+            child.wait()?;
+        } else if unsafe { libc::kill(self.pid as _, libc::SIGKILL) } < 0 {
+            return Err(std::io::Error::last_os_error().into());
+        }
         Ok(())
     }
 
     pub fn binary(&self, ip: usize) -> Option<&Binary> {
+        self.binary_index(ip).and_then(|i| self.binaries().nth(i))
+    }
+
+    /// Returns the index of the binary containing `ip` in `binaries`. Loaded binaries
+    /// take precedence over unloaded ones that were mapped at the same address.
+    pub fn binary_index(&self, ip: usize) -> Option<usize> {
+        let contains = |entry: &Binary| ip >= entry.start_addr && ip <= entry.end_addr;
         let i = match self.map.binary_search_by_key(&ip, |entry| entry.start_addr) {
             Ok(i) => i,
             Err(0) => 0,
             Err(i) => i - 1,
         };
-        let entry = &self.map[i];
-        if ip < entry.start_addr || ip > entry.end_addr {
-            None
-        } else {
-            Some(entry)
+        if self.map.get(i).map(contains).unwrap_or_default() {
+            return Some(i);
         }
+        // the last binary unloaded from an address was the last one mapped there.
+        let i = self.unloaded.iter().rposition(contains)?;
+        Some(self.map.len() + i)
+    }
+
+    /// Binaries unloaded with `dlclose` in the order they were unloaded.
+    pub fn unloaded(&self) -> &[Binary] {
+        &self.unloaded
+    }
+
+    /// Loaded binaries followed by the unloaded ones.
+    pub fn binaries(&self) -> impl Iterator<Item = &Binary> + '_ {
+        self.map.iter().chain(self.unloaded.iter())
+    }
+
+    /// Finds a loaded library by name, `libc` matches `/usr/lib/libc-2.33.so`.
+    pub fn library(&self, name: &str) -> Option<&Binary> {
+        let lib_name = format!("lib{}", name);
+        self.map.iter().find(|binary| {
+            let file_name = binary.elf.path().file_name().and_then(|f| f.to_str());
+            file_name
+                .map(|file_name| {
+                    [name, lib_name.as_str()].iter().any(|prefix| {
+                        file_name.starts_with(prefix)
+                            && file_name[prefix.len()..].starts_with(|c: char| c == '.' || c == '-')
+                    })
+                })
+                .unwrap_or_default()
+        })
     }
 
     pub fn resolve_symbol(&self, ip: usize) -> Result<Option<String>> {
@@ -119,6 +246,49 @@ impl BinaryInfo {
         Ok(None)
     }
 
+    /// Returns the functions inlined at `ip` innermost first, followed by the function
+    /// they were inlined into.
+    ///
+    /// Only the `leaf` frame of a stack is at the instruction that was executing, the
+    /// other frames are return addresses after a call. They're looked up one byte before,
+    /// inside of the call.
+    pub fn resolve_frames(&self, ip: usize, leaf: bool) -> Result<Vec<ResolvedFrame<'_>>> {
+        let ip = if leaf { ip } else { ip.saturating_sub(1) };
+        let mut resolved = vec![];
+        if let Some(entry) = self.binary(ip) {
+            let offset = ip - entry.start_addr;
+            if let Some(dwarf) = entry.dwarf.as_ref() {
+                let mut frames = dwarf.find_frames(offset)?;
+                while let Some(frame) = frames.next()? {
+                    if let Some(function) = frame.function {
+                        resolved.push(ResolvedFrame {
+                            name: function.demangle()?.to_string(),
+                            location: frame.location,
+                            inlined: true,
+                        });
+                    }
+                }
+            }
+            if resolved.is_empty() {
+                if let Some(symbol) = entry.elf.resolve_address(offset)? {
+                    resolved.push(ResolvedFrame {
+                        name: symbol.to_owned(),
+                        location: Some(Location {
+                            file: entry.elf.path().to_str(),
+                            line: None,
+                            column: None,
+                        }),
+                        inlined: true,
+                    });
+                }
+            }
+            if let Some(frame) = resolved.last_mut() {
+                frame.inlined = false;
+            }
+        }
+        Ok(resolved)
+    }
+
     pub fn resolve_location(&self, ip: usize) -> Result<Option<Location<'_>>> {
         if let Some(entry) = self.binary(ip) {
             let offset = ip - entry.start_addr;
@@ -160,6 +330,38 @@ impl BinaryInfo {
     }
 }
 
+/// Compares the `loaded` binaries with the `mapped` ones, returns the indices of the
+/// mapped binaries that aren't loaded and of the loaded binaries that were unmapped.
+fn diff(loaded: &[AddressEntry], mapped: &[AddressEntry]) -> (Vec<usize>, Vec<usize>) {
+    let added = (0..mapped.len())
+        .filter(|i| !loaded.contains(&mapped[*i]))
+        .collect();
+    let removed = (0..loaded.len())
+        .filter(|i| !mapped.contains(&loaded[*i]))
+        .collect();
+    (added, removed)
+}
+
+fn load_binaries(pid: u32) -> Result<Vec<Binary>> {
+    let address_map = AddressMap::load_pid(pid)?;
+    let mut map = vec![];
+    for entry in address_map.iter() {
+        map.push(load_binary(entry)?);
+    }
+    Ok(map)
+}
+
+fn load_binary(entry: &AddressEntry) -> Result<Binary> {
+    let elf = Elf::open(&entry.path)?;
+    let dwarf = elf.dwarf().ok();
+    Ok(Binary {
+        start_addr: entry.start_addr,
+        end_addr: entry.end_addr,
+        elf,
+        dwarf,
+    })
+}
+
 impl std::ops::Deref for BinaryInfo {
     type Target = [Binary];
 
@@ -188,3 +390,61 @@ impl std::fmt::Display for BinaryInfo {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn diff_reloaded_binaries() {
+        let entry = |path: &str, start_addr| AddressEntry {
+            path: path.into(),
+            start_addr,
+            end_addr: start_addr + 0x1000,
+        };
+        let loaded = [entry("/bin/a", 0x1000), entry("/lib/b.so", 0x3000)];
+        let mapped = [
+            entry("/bin/a", 0x1000),
+            entry("/lib/c.so", 0x3000),
+            entry("/lib/b.so", 0x5000),
+        ];
+        assert_eq!(diff(&loaded, &mapped), (vec![1, 2], vec![1]));
+        assert_eq!(diff(&loaded, &loaded), (vec![], vec![]));
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    #[inline(always)]
+    fn inlined() -> usize {
+        let ip;
+        unsafe { std::arch::asm!("lea {}, [rip]", out(reg) ip) };
+        ip
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    #[inline(never)]
+    fn caller() -> usize {
+        inlined()
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn resolve_inlined_frames() -> Result<()> {
+        let info = BinaryInfo::from_pid(std::process::id())?;
+        let ip = caller();
+        let names = |frames: Vec<ResolvedFrame>| {
+            frames
+                .into_iter()
+                .map(|frame| (frame.name, frame.inlined))
+                .collect::<Vec<_>>()
+        };
+        let frames = names(info.resolve_frames(ip, true)?);
+        assert!(frames.len() >= 2);
+        assert!(frames[0].0.contains("tests::inlined"));
+        assert!(frames[0].1);
+        assert!(frames[1].0.contains("tests::caller"));
+        assert!(!frames[1].1);
+        // a return address is resolved at the instruction before it.
+        assert_eq!(names(info.resolve_frames(ip + 1, false)?), frames);
+        Ok(())
+    }
+}
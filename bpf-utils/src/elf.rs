@@ -4,7 +4,7 @@ use ehframe::UnwindTable;
 use memmap::Mmap;
 use object::elf::FileHeader64;
 use object::read::elf::ElfFile;
-use object::{NativeEndian, Object, ObjectSymbol};
+use object::{NativeEndian, Object, ObjectSymbol, SymbolKind};
 use std::fs::File;
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
@@ -14,6 +14,10 @@ use thiserror::Error;
 #[error("Offset `{1}` out of range of `{1}`")]
 pub struct OffsetOutOfRange(String, usize);
 
+#[derive(Debug, Error)]
+#[error("`{0}` has no build id")]
+pub struct NoBuildId(PathBuf);
+
 struct InnerElf {
     _file: File,
     _mmap: Mmap,
@@ -51,7 +55,11 @@ impl Elf {
     }
 
     pub fn build_id(&self) -> Result<BuildId> {
-        Ok(BuildId::new(self.0.obj.build_id()?.unwrap()))
+        if let Some(build_id) = self.0.obj.build_id()? {
+            Ok(BuildId::new(build_id))
+        } else {
+            Err(NoBuildId(self.path().to_owned()).into())
+        }
     }
 
     pub fn unwind_table(&self) -> Result<UnwindTable> {
@@ -71,6 +79,36 @@ impl Elf {
         Ok(None)
     }
 
+    /// Returns the name of the symbol `name`, symbols mangled with the v0 scheme
+    /// match when they end with `name`.
+    pub fn find_symbol(&self, name: &str) -> Option<&str> {
+        let mut found = None;
+        for sym in self.0.obj.symbols() {
+            match sym.name() {
+                Ok(sym) if sym == name => return Some(sym),
+                Ok(sym) if sym.starts_with("_R") && sym.ends_with(name) => found = Some(sym),
+                _ => {}
+            }
+        }
+        found
+    }
+
+    /// Returns the symbol of the rust function `path` like `my_crate::handler`.
+    pub fn find_function(&self, path: &str) -> Option<&str> {
+        self.0.obj.symbols().find_map(|sym| {
+            if sym.kind() != SymbolKind::Text {
+                return None;
+            }
+            let name = sym.name().ok()?;
+            let demangled = addr2line::demangle(name, gimli::DW_LANG_Rust)?;
+            if without_hash(&demangled) == path {
+                Some(name)
+            } else {
+                None
+            }
+        })
+    }
+
     pub fn resolve_address(&self, address: usize) -> Result<Option<&str>> {
         for sym in self.0.obj.symbols() {
             if sym.address() <= address as u64 && sym.address() + sym.size() > address as u64 {
@@ -117,6 +155,33 @@ impl Elf {
     }*/
 }
 
+/// Removes the hash of legacy mangled names and the crate disambiguators of v0 mangled
+/// names, `foo[1a2b]::bar::h0123456789abcdef` becomes `foo::bar`.
+fn without_hash(name: &str) -> String {
+    let is_hash = |s: &str| !s.is_empty() && s.chars().all(|c| c.is_ascii_hexdigit());
+    let name = match name.rfind("::h") {
+        Some(i) if name.len() - i == 19 && is_hash(&name[(i + 3)..]) => &name[..i],
+        _ => name,
+    };
+    let mut stripped = String::with_capacity(name.len());
+    let mut rest = name;
+    while let Some(start) = rest.find('[') {
+        let end = rest[start..].find(']').map(|end| start + end);
+        match end {
+            Some(end) if is_hash(&rest[(start + 1)..end]) => {
+                stripped.push_str(&rest[..start]);
+                rest = &rest[(end + 1)..];
+            }
+            _ => {
+                stripped.push_str(&rest[..=start]);
+                rest = &rest[(start + 1)..];
+            }
+        }
+    }
+    stripped.push_str(rest);
+    stripped
+}
+
 type Reader = gimli::EndianRcSlice<gimli::RunTimeEndian>;
 
 pub struct Dwarf {
@@ -203,4 +268,12 @@ mod tests {
         //assert_eq!(location.line.unwrap(), 1);
         Ok(())
     }
+
+    #[test]
+    fn test_without_hash() {
+        assert_eq!(without_hash("foo::bar::h0123456789abcdef"), "foo::bar");
+        assert_eq!(without_hash("foo[1a2b3c]::bar"), "foo::bar");
+        assert_eq!(without_hash("<[u8]>::len"), "<[u8]>::len");
+        assert_eq!(without_hash("foo::hello"), "foo::hello");
+    }
 }
@@ -28,6 +28,18 @@ impl KernelSymbolTable {
         Ok(Self { symbols })
     }
 
+    /// Returns the symbol of the kernel function `name`, the compiler suffixes optimized
+    /// copies of functions with `.isra.0`, `.constprop.0` etc.
+    pub fn function(&self, name: &str) -> Option<&str> {
+        let symbols = self.symbols.iter().map(|ksym| ksym.symbol.as_str());
+        symbols.clone().find(|symbol| *symbol == name).or_else(|| {
+            symbols
+                .filter_map(|symbol| Some((symbol, symbol.strip_prefix(name)?)))
+                .find(|(_, suffix)| suffix.starts_with('.'))
+                .map(|(symbol, _)| symbol)
+        })
+    }
+
     pub fn symbol(&self, address: usize) -> (&str, usize) {
         let i = match self
             .symbols
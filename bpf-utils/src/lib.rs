@@ -4,6 +4,8 @@ pub mod elf;
 pub mod event;
 pub mod kallsyms;
 pub mod maps;
+pub mod ptrace;
 pub mod rlimit;
 pub mod syscall;
+pub mod task;
 pub use ehframe;
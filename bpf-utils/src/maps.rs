@@ -49,11 +49,12 @@ impl AddressMap {
 
     fn load<T: AsRef<Path>>(path: T) -> Result<Self> {
         let file = BufReader::new(File::open(path)?);
-        let mut entries = HashMap::<PathBuf, (usize, usize)>::new();
+        let mut entries = HashMap::<PathBuf, (usize, usize, bool)>::new();
         for line in file.lines() {
             let line = line?;
             let mut columns = line.split(' ');
             let address = columns.next().unwrap();
+            let executable = columns.next().unwrap().contains('x');
             let path = columns.last().unwrap();
             if !Path::new(path).exists() {
                 continue;
@@ -63,13 +64,17 @@ impl AddressMap {
             let start = usize::from_str_radix(start, 16)?;
             let end = address.next().unwrap();
             let end = usize::from_str_radix(end, 16)?;
-            let mut entry = entries.entry(path.into()).or_insert((start, end));
+            let mut entry = entries.entry(path.into()).or_insert((start, end, false));
             entry.0 = usize::min(entry.0, start);
             entry.1 = usize::max(entry.1, end);
+            entry.2 |= executable;
         }
+        // files that are mapped without executable pages (data files, locale archives,
+        // etc.) are not elf binaries we can unwind or symbolize.
         let mut map: Vec<AddressEntry> = entries
             .into_iter()
-            .map(|(path, (start, end))| AddressEntry {
+            .filter(|(_, (_, _, executable))| *executable)
+            .map(|(path, (start, end, _))| AddressEntry {
                 path,
                 start_addr: start,
                 end_addr: end,
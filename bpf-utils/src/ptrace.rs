@@ -0,0 +1,127 @@
+use anyhow::{bail, Result};
+use std::io::Error;
+use std::os::unix::process::CommandExt;
+use std::process::{Child, Command};
+
+/// Keeps the program under test stopped until the unwind tables are loaded.
+///
+/// Only the bits required by cargo-trace are implemented: stopping the program
+/// after `execve`, running it to a breakpoint and detaching from it again.
+///
+/// This replaces the `ptracer` crate, which spawns the program from a path and it's
+/// arguments only. The program is configured with a `Command` instead, which carries
+/// the environment, working directory and stdio of the program as well as the hooks
+/// run before `execve`, like moving the program into it's own process group.
+pub struct Ptracer {
+    child: Option<Child>,
+}
+
+impl Ptracer {
+    /// Spawns `cmd` and stops it before the first instruction is executed.
+    ///
+    /// Arguments, environment, working directory and stdio are taken from `cmd`.
+    pub fn spawn(mut cmd: Command) -> Result<Self> {
+        unsafe {
+            cmd.pre_exec(|| {
+                if ptrace(libc::PTRACE_TRACEME, 0, 0, 0) < 0 {
+                    return Err(Error::last_os_error());
+                }
+                Ok(())
+            });
+        }
+        let child = cmd.spawn()?;
+        let ptracer = Self { child: Some(child) };
+        // the child is stopped with a `SIGTRAP` after `execve` returns.
+        ptracer.wait_trap()?;
+        Ok(ptracer)
+    }
+
+    pub fn pid(&self) -> u32 {
+        self.child.as_ref().unwrap().id()
+    }
+
+    /// Runs the stopped program until it reaches `address`.
+    pub fn run_until(&mut self, address: usize) -> Result<()> {
+        let pid = self.pid() as libc::pid_t;
+        let word = peek_text(pid, address)?;
+        poke_text(pid, address, (word & !0xff) | 0xcc)?;
+        if unsafe { ptrace(libc::PTRACE_CONT, pid, 0, 0) } < 0 {
+            return Err(Error::last_os_error().into());
+        }
+        self.wait_trap()?;
+        poke_text(pid, address, word)?;
+        // rewind the instruction pointer to the start of the replaced instruction.
+        let mut regs: libc::user_regs_struct = unsafe { std::mem::zeroed() };
+        if unsafe { ptrace(libc::PTRACE_GETREGS, pid, 0, &mut regs as *mut _ as _) } < 0 {
+            return Err(Error::last_os_error().into());
+        }
+        regs.rip = address as _;
+        if unsafe { ptrace(libc::PTRACE_SETREGS, pid, 0, &regs as *const _ as _) } < 0 {
+            return Err(Error::last_os_error().into());
+        }
+        Ok(())
+    }
+
+    /// Detaches from the program and lets it run.
+    pub fn detach(mut self) -> Result<Child> {
+        let pid = self.pid() as libc::pid_t;
+        if unsafe { ptrace(libc::PTRACE_DETACH, pid, 0, 0) } < 0 {
+            return Err(Error::last_os_error().into());
+        }
+        Ok(self.child.take().unwrap())
+    }
+
+    fn wait_trap(&self) -> Result<()> {
+        let mut status = 0;
+        if unsafe { libc::waitpid(self.pid() as _, &mut status, 0) } < 0 {
+            return Err(Error::last_os_error().into());
+        }
+        if !libc::WIFSTOPPED(status) || libc::WSTOPSIG(status) != libc::SIGTRAP {
+            bail!("unexpected wait status 0x{:x}", status);
+        }
+        Ok(())
+    }
+}
+
+unsafe fn ptrace(
+    request: libc::c_uint,
+    pid: libc::pid_t,
+    addr: usize,
+    data: usize,
+) -> libc::c_long {
+    libc::ptrace(
+        request,
+        pid,
+        addr as *mut libc::c_void,
+        data as *mut libc::c_void,
+    )
+}
+
+fn peek_text(pid: libc::pid_t, address: usize) -> Result<usize> {
+    unsafe {
+        // `PTRACE_PEEKTEXT` returns the word, so errors can only be detected using `errno`.
+        *libc::__errno_location() = 0;
+        let word = ptrace(libc::PTRACE_PEEKTEXT, pid, address, 0);
+        if word == -1 && *libc::__errno_location() != 0 {
+            return Err(Error::last_os_error().into());
+        }
+        Ok(word as usize)
+    }
+}
+
+fn poke_text(pid: libc::pid_t, address: usize, word: usize) -> Result<()> {
+    if unsafe { ptrace(libc::PTRACE_POKETEXT, pid, address, word) } < 0 {
+        return Err(Error::last_os_error().into());
+    }
+    Ok(())
+}
+
+impl Drop for Ptracer {
+    fn drop(&mut self) {
+        // a program that is still being traced can't continue without us.
+        if let Some(child) = self.child.as_mut() {
+            let _ = child.kill();
+            let _ = child.wait();
+        }
+    }
+}
@@ -0,0 +1,69 @@
+use anyhow::Result;
+use std::collections::BTreeMap;
+
+/// Returns the ids of all threads of the process `pid`.
+pub fn threads(pid: u32) -> Result<Vec<u32>> {
+    let mut threads = vec![];
+    for entry in std::fs::read_dir(format!("/proc/{}/task", pid))? {
+        if let Ok(tid) = entry?.file_name().to_string_lossy().parse() {
+            threads.push(tid);
+        }
+    }
+    threads.sort_unstable();
+    Ok(threads)
+}
+
+/// Returns the name of the thread `tid` of the process `pid`.
+pub fn thread_name(pid: u32, tid: u32) -> Result<String> {
+    let comm = std::fs::read_to_string(format!("/proc/{}/task/{}/comm", pid, tid))?;
+    Ok(comm.trim_end().to_string())
+}
+
+/// Returns the names of all threads of the process `pid` by thread id.
+///
+/// Threads that exit while their names are read are skipped.
+pub fn thread_names(pid: u32) -> Result<BTreeMap<u32, String>> {
+    Ok(threads(pid)?
+        .into_iter()
+        .filter_map(|tid| Some((tid, thread_name(pid, tid).ok()?)))
+        .collect())
+}
+
+/// Returns the id of the process the thread `tid` belongs to.
+pub fn tgid(tid: u32) -> Result<u32> {
+    let status = std::fs::read_to_string(format!("/proc/{}/status", tid))?;
+    for line in status.lines() {
+        if let Some(tgid) = line.strip_prefix("Tgid:") {
+            return Ok(tgid.trim().parse()?);
+        }
+    }
+    Err(anyhow::anyhow!("no tgid in /proc/{}/status", tid))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn read_threads() {
+        let pid = std::process::id();
+        let (tx, rx) = std::sync::mpsc::channel();
+        let handle = std::thread::Builder::new()
+            .name("task-test".into())
+            .spawn(move || {
+                tx.send(()).unwrap();
+                std::thread::park();
+            })
+            .unwrap();
+        // the name is set by the thread itself after it started.
+        rx.recv().unwrap();
+        let names = thread_names(pid).unwrap();
+        assert!(names.contains_key(&pid));
+        assert!(names.values().any(|name| name == "task-test"));
+        for tid in names.keys() {
+            assert_eq!(tgid(*tid).unwrap(), pid);
+        }
+        handle.thread().unpark();
+        handle.join().unwrap();
+    }
+}
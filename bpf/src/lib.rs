@@ -1,7 +1,10 @@
 use anyhow::Result;
 pub use bpf_probes::*;
-use libbpf_rs::{Map, MapFlags, Object, ObjectBuilder, OpenObject};
+use libbpf_rs::{Map, MapFlags, Object, ObjectBuilder, OpenObject, PerfBuffer, PerfBufferBuilder};
+use std::cell::{Cell, RefCell};
 use std::marker::PhantomData;
+use std::rc::Rc;
+use std::time::Duration;
 use zerocopy::{AsBytes, FromBytes, LayoutVerified, Unaligned};
 
 pub type I16 = zerocopy::byteorder::I16<byteorder::NativeEndian>;
@@ -12,12 +15,13 @@ pub type U32 = zerocopy::byteorder::U32<byteorder::NativeEndian>;
 pub type U64 = zerocopy::byteorder::U64<byteorder::NativeEndian>;
 
 pub mod utils {
-    pub use bpf_utils::dylibs::BinaryInfo;
+    pub use bpf_utils::dylibs::{Binary, BinaryInfo, ResolvedFrame};
     pub use bpf_utils::ehframe;
     pub use bpf_utils::elf::{Dwarf, Elf};
     pub use bpf_utils::kallsyms::{KernelSymbol, KernelSymbolTable};
     pub use bpf_utils::maps::{AddressEntry, AddressMap};
     pub use bpf_utils::syscall::syscall_table;
+    pub use bpf_utils::task;
     pub use sudo;
 }
 
@@ -40,10 +44,18 @@ impl BpfBuilder {
         })
     }
 
+    /// Restricts perf events to the threads of `pid` and the tasks they spawn, other
+    /// probes fire for every process and need to be filtered by the bpf program.
     pub fn set_child_pid<T: Into<u32>>(&mut self, pid: T) {
         self.child_pid = Some(pid.into());
     }
 
+    /// Sets the capacity of `map`, it is fixed once the program is loaded.
+    pub fn set_max_entries(&mut self, map: &str, entries: u32) -> Result<()> {
+        self.new_obj.map(map)?.unwrap().set_max_entries(entries)?;
+        Ok(())
+    }
+
     pub fn attach_probe_str(&mut self, probe: &str, entry: &'static str) -> Result<()> {
         self.attach_probe(probe.parse()?, entry)
     }
@@ -63,21 +75,28 @@ impl BpfBuilder {
         let mut probes = vec![];
         for (probe, entry) in self.probes {
             let prog = obj.prog(entry)?.unwrap();
-            probes.extend(probe.attach(prog, self.child_pid)?);
+            let pid = if matches!(probe.prog_type(), ProgramType::PerfEvent) {
+                self.child_pid
+            } else {
+                None
+            };
+            probes.extend(probe.attach(prog, pid)?);
         }
-        Ok(Bpf {
-            obj,
-            _probes: probes,
-        })
+        Ok(Bpf { obj, probes })
     }
 }
 
 pub struct Bpf {
     obj: Object,
-    _probes: Vec<AttachedProbe>,
+    probes: Vec<AttachedProbe>,
 }
 
 impl Bpf {
+    /// Detaches all probes, the maps can still be read afterwards.
+    pub fn detach(&mut self) {
+        self.probes.clear();
+    }
+
     pub fn hash_map<K, V>(&mut self, map: &str) -> Result<BpfHashMap<'_, K, V>>
     where
         K: AsBytes + FromBytes + Unaligned + Clone,
@@ -96,6 +115,45 @@ impl Bpf {
     pub fn stack_trace(&mut self, map: &str) -> Result<BpfStackTrace<'_>> {
         Ok(BpfStackTrace::new(self.obj.map(map)?.unwrap()))
     }
+
+    /// Opens the perf event array `map` with buffers of `pages` pages per cpu.
+    pub fn perf_buffer<T>(&mut self, map: &str, pages: usize) -> Result<BpfPerfBuffer<T>>
+    where
+        T: FromBytes + Unaligned + Clone + 'static,
+    {
+        BpfPerfBuffer::new(self.obj.map(map)?.unwrap(), pages)
+    }
+
+    /// Sums the values of all cpus at `index` of the per cpu array `map`.
+    pub fn percpu_sum(&mut self, map: &str, index: u32) -> Result<u64> {
+        let map = self.obj.map(map)?.unwrap();
+        let cpus = unsafe { libbpf_sys::libbpf_num_possible_cpus() };
+        if cpus < 0 {
+            return Err(std::io::Error::from_raw_os_error(-cpus).into());
+        }
+        // the lookup writes the value of every possible cpu.
+        let mut values = vec![0u64; cpus as usize];
+        let ret = unsafe {
+            libbpf_sys::bpf_map_lookup_elem(
+                map.fd(),
+                &index as *const _ as *const _,
+                values.as_mut_ptr() as *mut _,
+            )
+        };
+        if ret < 0 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+        Ok(values.iter().sum())
+    }
+
+    /// Inserts the program `entry` into the program array `map` at `index`, so
+    /// that programs can tail call it.
+    pub fn set_tail_call(&mut self, map: &str, index: u32, entry: &str) -> Result<()> {
+        let fd = self.obj.prog(entry)?.unwrap().fd();
+        let map = self.obj.map(map)?.unwrap();
+        map.update(&index.to_ne_bytes(), &fd.to_ne_bytes(), MapFlags::ANY)?;
+        Ok(())
+    }
 }
 
 pub struct BpfHashMap<'a, K, V> {
@@ -130,6 +188,11 @@ where
         Ok(())
     }
 
+    pub fn remove(&mut self, key: &K) -> Result<()> {
+        self.map.delete(key.as_bytes())?;
+        Ok(())
+    }
+
     pub fn keys(&self) -> impl Iterator<Item = K> + '_ {
         self.map.keys().filter_map(|bytes| {
             LayoutVerified::<_, K>::new_unaligned(bytes.as_slice())
@@ -147,6 +210,52 @@ where
     }
 }
 
+/// Events written by the bpf program with `perf_event_output`.
+pub struct BpfPerfBuffer<T> {
+    buffer: PerfBuffer<'static>,
+    events: Rc<RefCell<Vec<T>>>,
+    lost: Rc<Cell<u64>>,
+}
+
+impl<T> BpfPerfBuffer<T>
+where
+    T: FromBytes + Unaligned + Clone + 'static,
+{
+    pub fn new(map: &Map, pages: usize) -> Result<Self> {
+        let events = Rc::new(RefCell::new(vec![]));
+        let lost = Rc::new(Cell::new(0));
+        let sample_events = events.clone();
+        let lost_events = lost.clone();
+        let buffer = PerfBufferBuilder::new(map)
+            .pages(pages)
+            .sample_cb(move |_cpu, bytes: &[u8]| {
+                // the kernel pads events to a multiple of 8 bytes.
+                if let Some((layout, _)) = LayoutVerified::<_, T>::new_unaligned_from_prefix(bytes)
+                {
+                    sample_events.borrow_mut().push(layout.into_ref().clone());
+                }
+            })
+            .lost_cb(move |_cpu, count| lost_events.set(lost_events.get() + count))
+            .build()?;
+        Ok(Self {
+            buffer,
+            events,
+            lost,
+        })
+    }
+
+    /// Waits up to `timeout` for events, returns the events read since the last poll.
+    pub fn poll(&mut self, timeout: Duration) -> Result<Vec<T>> {
+        self.buffer.poll(timeout)?;
+        Ok(std::mem::take(&mut *self.events.borrow_mut()))
+    }
+
+    /// Number of events that were lost because a buffer was full.
+    pub fn lost(&self) -> u64 {
+        self.lost.get()
+    }
+}
+
 const BPF_MAX_STACK_DEPTH: usize = 127;
 
 #[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
@@ -1,5 +1,10 @@
 fn main() {
-    std::thread::spawn(spawn_main);
+    std::thread::Builder::new()
+        .name("worker".into())
+        .spawn(spawn_main)
+        .unwrap()
+        .join()
+        .unwrap();
 }
 
 fn spawn_main() {
@@ -0,0 +1,23 @@
+use std::process::Command;
+
+fn main() {
+    // the child process runs the same example with an argument.
+    if std::env::args().nth(1).as_deref() == Some("child") {
+        spawn_main();
+        return;
+    }
+    let status = Command::new(std::env::current_exe().unwrap())
+        .arg("child")
+        .status()
+        .unwrap();
+    assert!(status.success());
+    spawn_main();
+}
+
+fn spawn_main() {
+    let mut i = 0;
+    for _ in 0..100000 {
+        i += 1;
+    }
+    println!("{}", i);
+}
@@ -1,25 +1,53 @@
 #![no_std]
 #![no_main]
 
-use bpf_helpers::{entry, map, program, sys, Array, HashMap, PidTgid};
+use bpf_helpers::{
+    entry, map, program, sys, Array, HashMap, Instant, PercpuArray, PerfEventArray, PidTgid,
+    ProgArray, StackTrace,
+};
 
 program!(0xFFFF_FFFE, b"GPL");
 
-// absolute maximum would be 512 byte stack size limit / 8 byte address = 64. but since
-// we need some stack for other variables this needs to be lower.
-const MAX_STACK_DEPTH: usize = 48;
+// stacks are unwound in per cpu storage, since they don't fit on the 512 byte bpf stack.
+const MAX_STACK_DEPTH: usize = 128;
+// frames unwound by a program before it tail calls itself to unwind the next frames,
+// limited by the number of instructions a program may have.
+const FRAMES_PER_CALL: usize = 16;
+// `BPF_F_CURRENT_CPU`, writes to the buffer of the cpu the program runs on.
+const CURRENT_CPU: u64 = 0xffff_ffff;
+// set in the flags of `clone` when the new task is a thread of the calling process.
+const CLONE_THREAD: u64 = 0x10000;
 const MAX_BIN_SEARCH_DEPTH: usize = 24;
 const EHFRAME_ENTRIES: usize = 0xff_ffff;
 
 #[derive(Clone, Copy)]
 #[repr(C)]
 pub struct Instruction {
-    op: u64,
+    op: u32,
+    arg: u32,
     offset: i64,
 }
 
+/// Rows of the unwind table of a process in the `PC`, `RIP` and `RSP` arrays.
+///
+/// `len` is zero until the unwind table of the process was loaded.
+#[derive(Clone, Copy, Default)]
+#[repr(C)]
+pub struct Process {
+    start: u32,
+    len: u32,
+    /// Number of times the process called exec, user space keeps the binaries of every
+    /// exec apart.
+    exec: u32,
+    _pad: u32,
+}
+
+/// Traced processes by pid, forked children inherit the unwind table of their parent.
 #[map]
-static CONFIG: Array<u32> = Array::with_max_entries(2);
+static PROCESSES: HashMap<u32, Process> = HashMap::with_max_entries(1024);
+/// Traced processes that loaded or unloaded libraries since their unwind table was loaded.
+#[map]
+static RELOAD: HashMap<u32, u32> = HashMap::with_max_entries(1024);
 #[map]
 static PC: Array<u64> = Array::with_max_entries(EHFRAME_ENTRIES);
 #[map]
@@ -27,66 +55,599 @@ static RIP: Array<Instruction> = Array::with_max_entries(EHFRAME_ENTRIES);
 #[map]
 static RSP: Array<Instruction> = Array::with_max_entries(EHFRAME_ENTRIES);
 
+#[derive(Clone, Copy)]
+#[repr(C)]
+pub struct StackKey {
+    probe: u32,
+    pid: u32,
+    tid: u32,
+    /// Id of the kernel stack in `KERNEL_STACKS` or `-1` if the sample was taken in
+    /// user space.
+    kernel_stack: i32,
+    /// Set to `1` when the stack was deeper than `MAX_STACK_DEPTH`.
+    truncated: u32,
+    /// `exec` of the process when the sample was taken.
+    exec: u32,
+    stack: [u64; MAX_STACK_DEPTH],
+}
+
+/// Sample sent to user space when streaming.
+#[derive(Clone, Copy)]
+#[repr(C)]
+pub struct Event {
+    /// Nanoseconds since boot.
+    time: u64,
+    weight: u64,
+    cpu: u32,
+    _pad: u32,
+    key: StackKey,
+}
+
+/// Stack that is being unwound.
+#[derive(Clone, Copy)]
+#[repr(C)]
+pub struct Unwind {
+    event: Event,
+    process: Process,
+    /// Number of frames in `event.key.stack`.
+    depth: u32,
+    _pad: u32,
+    /// Registers of the next frame, `rip` is zero when there are no more frames.
+    rip: u64,
+    rsp: u64,
+    rbp: u64,
+    /// Address of the allocation the stack is recorded for or zero.
+    allocation: u64,
+}
+
+#[map]
+static UNWIND: PercpuArray<Unwind> = PercpuArray::with_max_entries(1);
+/// `unwind_perf_event` and `unwind_kprobe`, programs can only tail call programs of the
+/// same type.
+#[map]
+static mut UNWIND_PERF_EVENT: ProgArray = ProgArray::with_max_entries(1);
 #[map]
-static USER_STACK: HashMap<[u64; MAX_STACK_DEPTH], u32> = HashMap::with_max_entries(1024);
+static mut UNWIND_KPROBE: ProgArray = ProgArray::with_max_entries(1);
 
-#[entry("perf_event")]
-fn perf_event(args: &bpf_perf_event_data) {
-    increment_stack_counter(&args.regs);
+/// Register the samples of a probe are weighted by, `0` counts samples, `1` to `6` are
+/// the arguments and `7` is the return value.
+#[map]
+static WEIGHTS: Array<u32> = Array::with_max_entries(8);
+
+/// Number of samples, sum of weights or nanoseconds off cpu by stack.
+#[map]
+static USER_STACK: HashMap<StackKey, u64> = HashMap::with_max_entries(1024);
+#[map]
+static KERNEL_STACKS: StackTrace = StackTrace::with_max_entries(1024);
+/// Set to `1` when samples are streamed through `SAMPLES` instead of being counted in
+/// `USER_STACK`.
+#[map]
+static STREAM: Array<u32> = Array::with_max_entries(1);
+/// Streamed samples, libbpf sizes perf event arrays to the number of cpus.
+#[map]
+static SAMPLES: PerfEventArray = PerfEventArray::with_max_entries(0);
+/// Samples dropped because `USER_STACK` was full, indexed by `DROPPED_SAMPLES` and
+/// `DROPPED_WEIGHT`, kernel stacks missing because `KERNEL_STACKS` was full and live
+/// allocations missing because `LIVE` was full.
+#[map]
+static STATS: PercpuArray<u64> = PercpuArray::with_max_entries(4);
+const DROPPED_SAMPLES: u32 = 0;
+const DROPPED_WEIGHT: u32 = 1;
+const DROPPED_KERNEL_STACKS: u32 = 2;
+const DROPPED_ALLOCATIONS: u32 = 3;
+/// Time the threads of the traced processes were switched out by tid.
+#[map]
+static OFFCPU_START: HashMap<u32, Instant> = HashMap::with_max_entries(10240);
+
+/// Time the traced function was entered by tid.
+#[map]
+static CALL_START: HashMap<u32, Instant> = HashMap::with_max_entries(10240);
+/// Number of calls by log2 of their latency in nanoseconds, per cpu so that calls
+/// returning on different cpus don't lose counts.
+#[map]
+static LATENCY: PercpuArray<u64> = PercpuArray::with_max_entries(65);
+/// Set to `1` when the latencies are broken down by the stack of the caller.
+#[map]
+static LATENCY_STACKS: Array<u32> = Array::with_max_entries(1);
+
+/// Address of an allocation, forked processes allocate at the same addresses.
+#[derive(Clone, Copy)]
+#[repr(C)]
+pub struct AllocationKey {
+    pid: u32,
+    _pad: u32,
+    ptr: u64,
+}
+
+#[derive(Clone, Copy)]
+#[repr(C)]
+pub struct Allocation {
+    size: u64,
+    /// Hash of the stack that allocated the memory.
+    stack: u64,
+}
+
+#[derive(Clone, Copy)]
+#[repr(C)]
+pub struct Pending {
+    size: u64,
+    /// Address passed to `realloc`, it is freed when the allocation succeeds.
+    realloc: u64,
+}
+
+/// Allocation in progress by tid.
+#[map]
+static PENDING: HashMap<u32, Pending> = HashMap::with_max_entries(10240);
+/// Threads inside of the rust allocator, which may call malloc itself.
+#[map]
+static IN_RUST: HashMap<u32, u32> = HashMap::with_max_entries(10240);
+/// Live allocations by address.
+#[map]
+static LIVE: HashMap<AllocationKey, Allocation> = HashMap::with_max_entries(65536);
+
+macro_rules! entries {
+    ($($probe:expr => $perf_event:ident, $kprobe:ident;)*) => {
+        $(
+            #[entry("perf_event")]
+            fn $perf_event(args: &bpf_perf_event_data) {
+                let unwind = unsafe { &mut UNWIND_PERF_EVENT };
+                increment_stack_counter($probe, args, &args.regs, unwind, 1, 0);
+            }
+
+            #[entry("kprobe")]
+            fn $kprobe(args: &pt_regs) {
+                let weight = weight($probe, args);
+                let unwind = unsafe { &mut UNWIND_KPROBE };
+                increment_stack_counter($probe, args, args, unwind, weight, 0);
+            }
+        )*
+    };
+}
+
+// every probe has it's own entry point so that samples can be tagged with the index of
+// the probe. this limits the number of probes that can be attached at the same time.
+entries! {
+    0 => perf_event_0, kprobe_0;
+    1 => perf_event_1, kprobe_1;
+    2 => perf_event_2, kprobe_2;
+    3 => perf_event_3, kprobe_3;
+    4 => perf_event_4, kprobe_4;
+    5 => perf_event_5, kprobe_5;
+    6 => perf_event_6, kprobe_6;
+    7 => perf_event_7, kprobe_7;
+}
+
+// runs in the context of the parent. the threads of a process are created with `clone`
+// too, they are sampled as part of their process and don't get an entry.
+#[entry("task:task_newtask")]
+fn task_newtask(args: &TaskNewtask) {
+    if args.clone_flags & CLONE_THREAD != 0 {
+        return;
+    }
+    let id = PidTgid::current();
+    if let Some(process) = PROCESSES.get(&id.pid()) {
+        let child = Process { exec: 0, ..process };
+        PROCESSES.insert(&(args.pid as u32), &child);
+    }
+}
+
+// the unwind table of the old program is useless after exec, the stacks are truncated
+// until user space loaded the new one.
+#[entry("sched:sched_process_exec")]
+fn sched_process_exec(_args: &SchedProcessExec) {
+    let id = PidTgid::current();
+    if let Some(process) = PROCESSES.get(&id.pid()) {
+        let process = Process {
+            exec: process.exec + 1,
+            ..Process::default()
+        };
+        PROCESSES.insert(&id.pid(), &process);
+    }
+}
+
+// the dynamic loader calls `_dl_debug_state` before and after it changes the list of
+// loaded libraries, user space reloads the unwind tables of the process.
+#[entry("kprobe")]
+fn dl_debug_state(_args: &pt_regs) {
+    let id = PidTgid::current();
+    if PROCESSES.get(&id.pid()).is_some() {
+        RELOAD.insert(&id.pid(), &1);
+    }
+}
+
+// the last switch of an exiting thread reports its `exit_state` in `prev_state`.
+const EXIT_DEAD: u64 = 0x10;
+const EXIT_ZOMBIE: u64 = 0x20;
+
+#[entry("sched:sched_switch")]
+fn sched_switch(args: &SchedSwitch) {
+    // the thread exits and is never switched back in.
+    if args.prev_state as u64 & (EXIT_DEAD | EXIT_ZOMBIE) != 0 {
+        return;
+    }
+    let id = PidTgid::current();
+    if PROCESSES.get(&id.pid()).is_some() {
+        OFFCPU_START.insert(&id.tid(), &Instant::now());
+    }
+}
+
+// runs in the context of the thread that is switched in. the user stack didn't change
+// while it was off cpu, so it is the stack where the thread blocked.
+#[entry("kprobe")]
+fn finish_task_switch(args: &pt_regs) {
+    let id = PidTgid::current();
+    if let Some(start) = OFFCPU_START.get(&id.tid()) {
+        OFFCPU_START.remove(&id.tid());
+        let offcpu = start.elapsed().as_nanos();
+        let unwind = unsafe { &mut UNWIND_KPROBE };
+        increment_stack_counter(0, args, args, unwind, offcpu, 0);
+    }
+}
+
+#[entry("kprobe")]
+fn latency_entry(_args: &pt_regs) {
+    let id = PidTgid::current();
+    if PROCESSES.get(&id.pid()).is_some() {
+        CALL_START.insert(&id.tid(), &Instant::now());
+    }
 }
 
+// a thread that exits in the traced function never returns from it.
+#[entry("sched:sched_process_exit")]
+fn sched_process_exit(_args: &SchedProcessExit) {
+    CALL_START.remove(&PidTgid::current().tid());
+}
+
+// recursive calls overwrite the start time, so only the innermost call is measured.
 #[entry("kprobe")]
-fn kprobe(args: &pt_regs) {
-    increment_stack_counter(args);
-}
-
-fn increment_stack_counter(regs: &sys::pt_regs) {
-    if let Some(pid) = CONFIG.get(1) {
-        if PidTgid::current().pid() == pid {
-            let mut stack = [0; MAX_STACK_DEPTH];
-            backtrace(regs, &mut stack);
-            let mut count = USER_STACK.get(&stack).unwrap_or_default();
-            count += 1;
-            USER_STACK.insert(&stack, &count);
+fn latency_return(args: &pt_regs) {
+    let tid = PidTgid::current().tid();
+    if let Some(start) = CALL_START.get(&tid) {
+        CALL_START.remove(&tid);
+        let latency = start.elapsed().as_nanos();
+        let bucket = 64 - latency.leading_zeros();
+        if let Some(count) = unsafe { LATENCY.lookup(&bucket).as_mut() } {
+            *count += 1;
+        }
+        if LATENCY_STACKS.get(0) == Some(1) {
+            // the samples of the probe with the index of the bucket.
+            let unwind = unsafe { &mut UNWIND_KPROBE };
+            increment_stack_counter(bucket, args, args, unwind, 1, 0);
         }
     }
 }
 
-fn backtrace(regs: &sys::pt_regs, stack: &mut [u64; MAX_STACK_DEPTH]) {
-    let mut rip = regs.rip;
-    let mut rsp = regs.rsp;
-    for d in 0..MAX_STACK_DEPTH {
-        stack[d] = rip;
-        if rip == 0 {
-            break;
+#[entry("kprobe")]
+fn malloc_entry(args: &pt_regs) {
+    if !in_rust() {
+        record_size(args.rdi, 0);
+    }
+}
+
+#[entry("kprobe")]
+fn calloc_entry(args: &pt_regs) {
+    if !in_rust() {
+        record_size(args.rdi.wrapping_mul(args.rsi), 0);
+    }
+}
+
+#[entry("kprobe")]
+fn realloc_entry(args: &pt_regs) {
+    if !in_rust() {
+        record_size(args.rsi, args.rdi);
+    }
+}
+
+#[entry("kprobe")]
+fn free_entry(args: &pt_regs) {
+    if !in_rust() {
+        dealloc(args.rdi);
+    }
+}
+
+#[entry("kprobe")]
+fn alloc_return(args: &pt_regs) {
+    if !in_rust() {
+        record_allocation(args);
+    }
+}
+
+// `__rust_alloc` and `__rust_alloc_zeroed`.
+#[entry("kprobe")]
+fn rust_alloc_entry(args: &pt_regs) {
+    enter_rust();
+    record_size(args.rdi, 0);
+}
+
+#[entry("kprobe")]
+fn rust_realloc_entry(args: &pt_regs) {
+    enter_rust();
+    record_size(args.rcx, args.rdi);
+}
+
+#[entry("kprobe")]
+fn rust_dealloc_entry(args: &pt_regs) {
+    enter_rust();
+    dealloc(args.rdi);
+}
+
+#[entry("kprobe")]
+fn rust_alloc_return(args: &pt_regs) {
+    IN_RUST.remove(&PidTgid::current().tid());
+    record_allocation(args);
+}
+
+#[entry("kprobe")]
+fn rust_dealloc_return(_args: &pt_regs) {
+    IN_RUST.remove(&PidTgid::current().tid());
+}
+
+// only threads of the traced processes are tracked, like in `record_size`.
+fn enter_rust() {
+    let id = PidTgid::current();
+    if PROCESSES.get(&id.pid()).is_some() {
+        IN_RUST.insert(&id.tid(), &1);
+    }
+}
+
+fn in_rust() -> bool {
+    IN_RUST.get(&PidTgid::current().tid()).is_some()
+}
+
+fn record_size(size: u64, realloc: u64) {
+    let id = PidTgid::current();
+    if PROCESSES.get(&id.pid()).is_some() {
+        PENDING.insert(&id.tid(), &Pending { size, realloc });
+    }
+}
+
+// the stack is unwound when the allocator returned, so the allocator itself isn't part
+// of the stack.
+fn record_allocation(regs: &sys::pt_regs) {
+    let tid = PidTgid::current().tid();
+    if let Some(pending) = PENDING.get(&tid) {
+        PENDING.remove(&tid);
+        // a failed `realloc` keeps the old memory, unless it was resized to zero.
+        if regs.rax != 0 || pending.size == 0 {
+            dealloc(pending.realloc);
+        }
+        if regs.rax == 0 {
+            return;
         }
-        let i = binary_search(rip);
+        let unwind = unsafe { &mut UNWIND_KPROBE };
+        increment_stack_counter(0, regs, regs, unwind, pending.size, regs.rax);
+    }
+}
 
-        let ins = if let Some(ins) = RSP.get(i) {
-            ins
-        } else {
-            break;
-        };
-        let cfa = if let Some(cfa) = execute_instruction(&ins, rip, rsp, 0) {
-            cfa
-        } else {
-            break;
+fn dealloc(ptr: u64) {
+    if ptr != 0 {
+        LIVE.remove(&allocation_key(ptr));
+    }
+}
+
+fn allocation_key(ptr: u64) -> AllocationKey {
+    AllocationKey {
+        pid: PidTgid::current().pid(),
+        _pad: 0,
+        ptr,
+    }
+}
+
+fn weight(probe: u32, regs: &sys::pt_regs) -> u64 {
+    match WEIGHTS.get(probe).unwrap_or_default() {
+        1 => regs.rdi,
+        2 => regs.rsi,
+        3 => regs.rdx,
+        4 => regs.rcx,
+        5 => regs.r8,
+        6 => regs.r9,
+        // errors are returned as negative values.
+        7 => (regs.rax as i64).max(0) as u64,
+        _ => 1,
+    }
+}
+
+/// Adds `weight` to the stack of the current thread once it is unwound, when unwinding
+/// the stack takes more than one program `unwind` is tail called.
+///
+/// When `allocation` isn't zero the allocation is recorded as live.
+fn increment_stack_counter<C>(
+    probe: u32,
+    ctx: &C,
+    regs: &sys::pt_regs,
+    unwind: &mut ProgArray,
+    weight: u64,
+    allocation: u64,
+) {
+    let id = PidTgid::current();
+    let process = if let Some(process) = PROCESSES.get(&id.pid()) {
+        process
+    } else {
+        return;
+    };
+    let state = if let Some(state) = unsafe { UNWIND.lookup(&0).as_mut() } {
+        state
+    } else {
+        return;
+    };
+    let event = &mut state.event;
+    event.time = unsafe { sys::bpf_ktime_get_ns() };
+    event.weight = weight;
+    event.cpu = unsafe { sys::bpf_get_smp_processor_id() };
+    event.key.probe = probe;
+    event.key.pid = id.pid();
+    event.key.tid = id.tid();
+    event.key.kernel_stack = -1;
+    event.key.truncated = 0;
+    event.key.exec = process.exec;
+    for ip in event.key.stack.iter_mut() {
+        *ip = 0;
+    }
+    if regs.cs & 3 == 0 {
+        let ctx = ctx as *const C as *const _;
+        match KERNEL_STACKS.stack_id(ctx, StackTrace::KERNEL_STACK) {
+            Ok(id) => event.key.kernel_stack = id as i32,
+            Err(_) => count(DROPPED_KERNEL_STACKS, 1),
+        }
+    }
+    let (rip, rsp, rbp) = user_regs(regs).unwrap_or_default();
+    state.process = process;
+    state.depth = 0;
+    state.rip = rip;
+    state.rsp = rsp;
+    state.rbp = rbp;
+    state.allocation = allocation;
+    continue_unwind(ctx, unwind);
+}
+
+#[entry("perf_event")]
+fn unwind_perf_event(args: &bpf_perf_event_data) {
+    continue_unwind(args, unsafe { &mut UNWIND_PERF_EVENT });
+}
+
+#[entry("kprobe")]
+fn unwind_kprobe(args: &pt_regs) {
+    continue_unwind(args, unsafe { &mut UNWIND_KPROBE });
+}
+
+fn continue_unwind<C>(ctx: &C, unwind: &mut ProgArray) {
+    let state = if let Some(state) = unsafe { UNWIND.lookup(&0).as_mut() } {
+        state
+    } else {
+        return;
+    };
+    backtrace(state);
+    if state.rip != 0 && (state.depth as usize) < MAX_STACK_DEPTH {
+        // doesn't return unless the tail call limit was reached.
+        let _ = unwind.tail_call(ctx, 0);
+    }
+    let event = &mut state.event;
+    event.key.truncated = (state.rip != 0) as u32;
+    if STREAM.get(0) == Some(1) {
+        SAMPLES.perf_event_output(ctx, event, CURRENT_CPU);
+    } else {
+        let mut count = USER_STACK.get(&event.key).unwrap_or_default();
+        count += event.weight;
+        if USER_STACK.try_insert(&event.key, &count).is_err() {
+            drop_sample(event.weight);
+            return;
+        }
+    }
+    if state.allocation != 0 {
+        let allocation = Allocation {
+            size: event.weight,
+            stack: stack_hash(&event.key),
         };
+        if LIVE
+            .try_insert(&allocation_key(state.allocation), &allocation)
+            .is_err()
+        {
+            count(DROPPED_ALLOCATIONS, 1);
+        }
+    }
+}
+
+/// Counts a sample that couldn't be recorded.
+fn drop_sample(weight: u64) {
+    count(DROPPED_SAMPLES, 1);
+    count(DROPPED_WEIGHT, weight);
+}
 
-        let ins = if let Some(ins) = RIP.get(i) {
-            ins
+/// Adds `n` to the counter `stat` of `STATS`, the counters are per cpu so they don't
+/// need to be incremented atomically.
+fn count(stat: u32, n: u64) {
+    if let Some(count) = unsafe { STATS.lookup(&stat).as_mut() } {
+        *count += n;
+    }
+}
+
+/// FNV-1a hash of the pid, tid and user stack, user space hashes samples the same way.
+fn stack_hash(key: &StackKey) -> u64 {
+    let mut hash = 0xcbf2_9ce4_8422_2325u64;
+    let id = key.pid as u64 | (key.tid as u64) << 32;
+    hash = (hash ^ id).wrapping_mul(0x100_0000_01b3);
+    for ip in key.stack.iter() {
+        hash = (hash ^ ip).wrapping_mul(0x100_0000_01b3);
+    }
+    hash
+}
+
+/// Returns the user space `rip`, `rsp` and `rbp`. In kernel mode they're read from the
+/// registers the current task saved on entry to the kernel.
+fn user_regs(regs: &sys::pt_regs) -> Option<(u64, u64, u64)> {
+    if regs.cs & 3 == 3 {
+        return Some((regs.rip, regs.rsp, regs.rbp));
+    }
+    let task = unsafe { sys::bpf_get_current_task_btf() };
+    let saved = unsafe { sys::bpf_task_pt_regs(task) } as *const sys::pt_regs;
+    // kernel threads have no user regs.
+    let cs = read(unsafe { core::ptr::addr_of!((*saved).cs) })?;
+    if cs & 3 != 3 {
+        return None;
+    }
+    let rip = read(unsafe { core::ptr::addr_of!((*saved).rip) })?;
+    let rsp = read(unsafe { core::ptr::addr_of!((*saved).rsp) })?;
+    let rbp = read(unsafe { core::ptr::addr_of!((*saved).rbp) })?;
+    Some((rip, rsp, rbp))
+}
+
+fn read(ptr: *const u64) -> Option<u64> {
+    let mut res: u64 = 0;
+    if unsafe { sys::bpf_probe_read(&mut res as *mut _ as *mut _, 8, ptr as *const _) } == 0 {
+        Some(res)
+    } else {
+        None
+    }
+}
+
+/// Unwinds the next `FRAMES_PER_CALL` frames.
+fn backtrace(state: &mut Unwind) {
+    for _ in 0..FRAMES_PER_CALL {
+        let slot = if let Some(slot) = state.event.key.stack.get_mut(state.depth as usize) {
+            slot
         } else {
-            break;
+            return;
         };
-        rip = execute_instruction(&ins, rip, rsp, cfa).unwrap_or_default();
-        rsp = cfa;
+        *slot = state.rip;
+        state.depth += 1;
+        match step(state.rip, state.rsp, state.rbp, &state.process) {
+            Some((rip, rsp, rbp)) if rip != 0 => {
+                state.rip = rip;
+                state.rsp = rsp;
+                state.rbp = rbp;
+            }
+            _ => {
+                state.rip = 0;
+                return;
+            }
+        }
     }
 }
 
-fn binary_search(rip: u64) -> u32 {
-    let mut left = 0;
-    let mut right = CONFIG.get(0).unwrap_or(1) - 1;
-    let mut i = 0;
+/// Returns the `rip`, `rsp` and `rbp` of the caller.
+fn step(rip: u64, rsp: u64, rbp: u64, process: &Process) -> Option<(u64, u64, u64)> {
+    if rip == 0 || process.len == 0 {
+        return None;
+    }
+    let i = binary_search(rip, process);
+    let regs = Regs { rip, rsp, rbp };
+    let ins = RSP.get(i)?;
+    let cfa = execute_instruction(&ins, &regs, 0)?;
+    let ins = RIP.get(i)?;
+    let rip = execute_instruction(&ins, &regs, cfa).unwrap_or_default();
+    // `arg` of `rip` is the offset of the saved `rbp` below the cfa, or `0` when `rbp`
+    // keeps its value.
+    let rbp = match ins.arg {
+        0 => rbp,
+        offset => read((cfa - offset as u64) as *const u64).unwrap_or_default(),
+    };
+    Some((rip, cfa, rbp))
+}
+
+fn binary_search(rip: u64, process: &Process) -> u32 {
+    let mut left = process.start;
+    let mut right = process.start + process.len - 1;
+    let mut i = left;
     for _ in 0..MAX_BIN_SEARCH_DEPTH {
         if left > right {
             break;
@@ -102,19 +663,28 @@ fn binary_search(rip: u64) -> u32 {
     i
 }
 
-fn execute_instruction(ins: &Instruction, rip: u64, rsp: u64, cfa: u64) -> Option<u64> {
+/// Registers of the frame that is being unwound.
+struct Regs {
+    rip: u64,
+    rsp: u64,
+    rbp: u64,
+}
+
+fn execute_instruction(ins: &Instruction, regs: &Regs, cfa: u64) -> Option<u64> {
     match ins.op {
-        1 => {
-            let unsafe_ptr = (cfa as i64 + ins.offset as i64) as *const core::ffi::c_void;
-            let mut res: u64 = 0;
-            if unsafe { sys::bpf_probe_read(&mut res as *mut _ as *mut _, 8, unsafe_ptr) } == 0 {
-                Some(res)
+        1 => read((cfa as i64 + ins.offset as i64) as *const u64),
+        2 => Some((regs.rip as i64 + ins.offset as i64) as u64),
+        3 => Some((regs.rsp as i64 + ins.offset as i64) as u64),
+        4 => Some((regs.rbp as i64 + ins.offset as i64) as u64),
+        // a plt entry pushed the index of its relocation once `rip & 15` reaches `arg`.
+        5 => {
+            let pushed = if regs.rip & 15 >= ins.arg as u64 {
+                8
             } else {
-                None
-            }
+                0
+            };
+            Some((regs.rsp as i64 + ins.offset + pushed) as u64)
         }
-        2 => Some((rip as i64 + ins.offset as i64) as u64),
-        3 => Some((rsp as i64 + ins.offset as i64) as u64),
         _ => None,
     }
 }
@@ -0,0 +1,178 @@
+//! Source listings annotated with the samples of each line.
+//!
+//! Only the leaf of the user stack is counted, so the counts are self samples. Lines
+//! of inlined functions are attributed to the inlined function.
+use crate::process::Processes;
+use crate::sample::{Thread, Unit};
+use anyhow::Result;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fs::File;
+
+/// Number of lines shown around a line with samples.
+const CONTEXT: u32 = 2;
+
+#[derive(Serialize)]
+struct Annotations<'a> {
+    title: &'a str,
+    unit: Unit,
+    total: u64,
+    files: Vec<AnnotatedFile>,
+}
+
+#[derive(Debug, Eq, PartialEq, Serialize)]
+struct AnnotatedFile {
+    path: String,
+    count: u64,
+    /// Lines with samples, sorted by line number.
+    lines: Vec<AnnotatedLine>,
+}
+
+#[derive(Debug, Eq, PartialEq, Serialize)]
+struct AnnotatedLine {
+    line: u32,
+    count: u64,
+}
+
+/// Sums the samples per file and line, files with the most samples first.
+fn annotate(processes: &Processes, threads: &[Thread]) -> Result<Vec<AnnotatedFile>> {
+    let mut files = BTreeMap::<String, BTreeMap<u32, u64>>::new();
+    for thread in threads {
+        for sample in &thread.samples {
+            let info = processes.get(sample.pid, sample.exec);
+            let ip = sample.stack[0].get() as usize;
+            if ip == 0 {
+                continue;
+            }
+            let location = match info.resolve_location(ip)? {
+                Some(location) => location,
+                None => continue,
+            };
+            if let (Some(file), Some(line)) = (location.file, location.line) {
+                let lines = files.entry(file.to_string()).or_default();
+                *lines.entry(line).or_default() += sample.count;
+            }
+        }
+    }
+    let mut files: Vec<_> = files
+        .into_iter()
+        .map(|(path, lines)| AnnotatedFile {
+            path,
+            count: lines.values().sum(),
+            lines: lines
+                .into_iter()
+                .map(|(line, count)| AnnotatedLine { line, count })
+                .collect(),
+        })
+        .collect();
+    files.sort_by(|a, b| b.count.cmp(&a.count).then_with(|| a.path.cmp(&b.path)));
+    Ok(files)
+}
+
+/// Renders the lines with samples and the lines around them, `source` is `None` when
+/// the file couldn't be read.
+fn render(file: &AnnotatedFile, total: u64, source: Option<&str>) -> String {
+    let percent = |count: u64| count as f64 * 100.0 / total.max(1) as f64;
+    let mut out = format!("{} ({:.2}%)\n", file.path, percent(file.count));
+    let source: Vec<_> = source.map(|s| s.lines().collect()).unwrap_or_default();
+    let counts: BTreeMap<_, _> = file.lines.iter().map(|l| (l.line, l.count)).collect();
+    let mut last = None;
+    for line in counts.keys() {
+        let start = line.saturating_sub(CONTEXT).max(1);
+        let end = line + CONTEXT;
+        let start = last.map(|last| start.max(last + 1)).unwrap_or(start);
+        if let Some(last) = last {
+            if start > last + 1 {
+                out.push_str("     ...\n");
+            }
+        }
+        for n in start..=end {
+            let text = source.get(n as usize - 1);
+            if text.is_none() && !counts.contains_key(&n) {
+                continue;
+            }
+            let count = match counts.get(&n) {
+                Some(count) => format!("{:>7.2}%", percent(*count)),
+                None => String::new(),
+            };
+            out.push_str(&format!(
+                "{:>8} {:>6} | {}\n",
+                count,
+                n,
+                text.copied().unwrap_or_default()
+            ));
+            last = Some(n);
+        }
+    }
+    out
+}
+
+/// Prints the annotated source of the files with samples and writes the samples of
+/// each line as json to `path`.
+pub fn write_annotate(
+    processes: &Processes,
+    threads: &[Thread],
+    unit: Unit,
+    title: &str,
+    path: &str,
+) -> Result<()> {
+    let files = annotate(processes, threads)?;
+    let total = threads
+        .iter()
+        .flat_map(|thread| &thread.samples)
+        .map(|sample| sample.count)
+        .sum();
+    println!("{} ({} {})", title, total, unit);
+    for file in &files {
+        let source = std::fs::read_to_string(&file.path).ok();
+        print!("\n{}", render(file, total, source.as_deref()));
+    }
+    let annotations = Annotations {
+        title,
+        unit,
+        total,
+        files,
+    };
+    serde_json::to_writer(File::create(path)?, &annotations)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn render_lines_with_context() {
+        let file = AnnotatedFile {
+            path: "src/main.rs".into(),
+            count: 3,
+            lines: vec![
+                AnnotatedLine { line: 2, count: 1 },
+                AnnotatedLine { line: 9, count: 2 },
+            ],
+        };
+        let source = (1..=12)
+            .map(|n| format!("line {}\n", n))
+            .collect::<String>();
+        let rendered = render(&file, 4, Some(&source));
+        let lines: Vec<_> = rendered.lines().map(|line| line.trim_end()).collect();
+        assert_eq!(
+            lines,
+            [
+                "src/main.rs (75.00%)",
+                "              1 | line 1",
+                "  25.00%      2 | line 2",
+                "              3 | line 3",
+                "              4 | line 4",
+                "     ...",
+                "              7 | line 7",
+                "              8 | line 8",
+                "  50.00%      9 | line 9",
+                "             10 | line 10",
+                "             11 | line 11",
+            ]
+        );
+        let rendered = render(&file, 4, None);
+        assert_eq!(rendered.lines().count(), 4);
+    }
+}
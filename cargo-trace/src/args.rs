@@ -0,0 +1,601 @@
+use crate::sample::Unit;
+use anyhow::{bail, Result};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+
+/// Cargo target to trace.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Target {
+    Bin(String),
+    Example(String),
+    Test(String),
+    Bench(String),
+}
+
+impl Target {
+    /// Target kind as reported by `cargo build --message-format=json`.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Self::Bin(_) => "bin",
+            Self::Example(_) => "example",
+            Self::Test(_) => "test",
+            Self::Bench(_) => "bench",
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        match self {
+            Self::Bin(name) | Self::Example(name) | Self::Test(name) | Self::Bench(name) => name,
+        }
+    }
+}
+
+/// Output format of the profile.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum Format {
+    /// Collapsed stacks and a flamegraph svg.
+    #[default]
+    Flamegraph,
+    /// pprof protobuf profile.
+    Pprof,
+    /// speedscope json profile.
+    Speedscope,
+    /// Firefox Profiler json profile.
+    Firefox,
+    /// Unsymbolized profile that can be symbolized later with `cargo trace report`.
+    Raw,
+    /// Source listings with the samples of each line and the same as json.
+    Annotate,
+}
+
+impl FromStr for Format {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s {
+            "flamegraph" => Self::Flamegraph,
+            "pprof" => Self::Pprof,
+            "speedscope" => Self::Speedscope,
+            "firefox" => Self::Firefox,
+            "raw" => Self::Raw,
+            "annotate" => Self::Annotate,
+            _ => bail!("unsupported format `{}`", s),
+        })
+    }
+}
+
+/// How the samples of different threads are combined in flamegraphs and pprof profiles.
+///
+/// speedscope and Firefox Profiler profiles always have a track per thread.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum ThreadMode {
+    /// Samples of all threads are merged.
+    #[default]
+    Merge,
+    /// Samples are grouped below a root frame for each thread.
+    Group,
+    /// Every thread is written to a separate file.
+    Split,
+}
+
+impl FromStr for ThreadMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s {
+            "merge" => Self::Merge,
+            "group" => Self::Group,
+            "split" => Self::Split,
+            _ => bail!("unsupported thread mode `{}`", s),
+        })
+    }
+}
+
+/// What the samples of a probe are weighted by, given as `weight=arg0` after the probe.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum Weight {
+    /// Every sample counts once.
+    #[default]
+    Count,
+    /// Argument `arg0` to `arg5` of the probed function.
+    Arg(u8),
+    /// Return value of the probed function.
+    Retval,
+}
+
+impl Weight {
+    /// Register the bpf program weights samples by, `0` counts samples.
+    pub fn register(&self) -> u32 {
+        match self {
+            Self::Count => 0,
+            Self::Arg(i) => *i as u32 + 1,
+            Self::Retval => 7,
+        }
+    }
+
+    /// Unit of the samples of a probe with this weight.
+    pub fn unit(&self) -> Unit {
+        match self {
+            Self::Count => Unit::Samples,
+            _ => Unit::Weight,
+        }
+    }
+}
+
+impl FromStr for Weight {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s {
+            "count" => Self::Count,
+            "retval" => Self::Retval,
+            _ => match s.strip_prefix("arg").map(|i| i.parse()) {
+                Some(Ok(i)) if i < 6 => Self::Arg(i),
+                _ => bail!("unsupported weight `{}`, expected `arg0..5` or `retval`", s),
+            },
+        })
+    }
+}
+
+/// Splits a probe like `uprobe:libc:malloc weight=arg0` into the probe and it's weight.
+pub fn split_weight(probe: &str) -> Result<(&str, Weight)> {
+    let mut iter = probe.split_whitespace();
+    let name = iter.next().unwrap_or_default();
+    let mut weight = Weight::Count;
+    for arg in iter {
+        match arg.strip_prefix("weight=") {
+            Some(value) => weight = value.parse()?,
+            None => bail!("unexpected `{}` after probe `{}`", arg, name),
+        }
+    }
+    // the arguments are overwritten by the time the function returns.
+    let returns = name.starts_with("uretprobe:") || name.starts_with("kretprobe:");
+    match weight {
+        Weight::Retval if !returns => bail!("`{}` has no return value", name),
+        Weight::Arg(i) if returns => bail!("`{}` has no `arg{}` when it returns", name, i),
+        _ => Ok((name, weight)),
+    }
+}
+
+/// What `cargo trace` should do.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub enum Mode {
+    /// Trace a program and write a profile.
+    #[default]
+    Record,
+    /// Trace a program and write a profile of the time its threads were blocked.
+    OffCpu,
+    /// Trace a program and write profiles of the allocated and still live memory.
+    Heap,
+    /// Trace the calls of a function and print a histogram of their latency.
+    Latency { function: String },
+    /// Compare two collapsed stack files.
+    Diff { before: PathBuf, after: PathBuf },
+    /// Symbolize a raw profile.
+    Report { profile: PathBuf },
+}
+
+/// Command line arguments of `cargo trace`.
+///
+/// Arguments that cargo-trace doesn't know about are forwarded to `cargo build`,
+/// arguments after `--` are passed to the program under test.
+#[derive(Debug, Default, Eq, PartialEq)]
+pub struct Args {
+    pub mode: Mode,
+    /// Probes to attach, followed by their weight if they aren't counted.
+    pub probes: Vec<String>,
+    /// Arguments forwarded to `cargo build`.
+    pub cargo: Vec<String>,
+    /// Target selected with `--bin`, `--example`, `--test` or `--bench`.
+    pub target: Option<Target>,
+    /// Process to attach to instead of spawning the program.
+    pub pid: Option<u32>,
+    /// Environment variables of the program.
+    pub env: Vec<(String, String)>,
+    /// Working directory of the program.
+    pub cwd: Option<PathBuf>,
+    /// File to use as stdin of the program.
+    pub stdin: Option<PathBuf>,
+    /// File to use as stdout of the program.
+    pub stdout: Option<PathBuf>,
+    /// Arguments of the program.
+    pub program_args: Vec<String>,
+    /// Stop sampling after this duration.
+    pub duration: Option<Duration>,
+    /// Kill the program when sampling stops before it exited.
+    pub kill: bool,
+    /// Output format.
+    pub format: Format,
+    /// How samples of different threads are combined.
+    pub threads: ThreadMode,
+    /// Number of functions listed in reports.
+    pub top: Option<usize>,
+    /// Show the hottest functions while the program is running.
+    pub live: bool,
+    /// Break latency histograms down by the stack of the caller.
+    pub by_stack: bool,
+    /// Number of distinct stacks the bpf program can record.
+    pub map_size: Option<u32>,
+    /// Stream every sample to user space instead of counting them in the bpf program.
+    pub stream: bool,
+    /// Directory containing debug symbols used by `cargo trace report`.
+    pub debug_dir: Option<PathBuf>,
+}
+
+impl Args {
+    pub fn from_env() -> Result<Self> {
+        Self::parse(std::env::args().skip(1))
+    }
+
+    pub fn parse(args: impl IntoIterator<Item = String>) -> Result<Self> {
+        let mut iter = args.into_iter().peekable();
+        // when invoked as `cargo trace` the first argument is the subcommand name.
+        if iter.peek().map(|arg| arg.as_str()) == Some("trace") {
+            iter.next();
+        }
+        let mut args = Self::default();
+        let mut positional = vec![];
+        while let Some(arg) = iter.next() {
+            if arg == "--" {
+                args.program_args = iter.collect();
+                break;
+            }
+            if !arg.starts_with('-') {
+                positional.push(arg);
+                continue;
+            }
+            let (name, inline) = match arg.find('=') {
+                Some(i) if arg.starts_with("--") => (&arg[..i], Some(arg[(i + 1)..].to_string())),
+                _ => (arg.as_str(), None),
+            };
+            let mut value = || inline.clone().or_else(|| iter.next()).ok_or(name);
+            match name {
+                "--pid" => args.pid = Some(value().map_err(expected)?.parse()?),
+                "--env" => {
+                    let var = value().map_err(expected)?;
+                    let mut split = var.splitn(2, '=');
+                    let key = split.next().unwrap().to_string();
+                    if let Some(value) = split.next() {
+                        args.env.push((key, value.to_string()));
+                    } else {
+                        bail!("expected `--env KEY=VALUE` got `{}`", var);
+                    }
+                }
+                "--duration" => args.duration = Some(parse_duration(&value().map_err(expected)?)?),
+                "--kill" => args.kill = true,
+                "--live" => args.live = true,
+                "--by-stack" => args.by_stack = true,
+                "--stream" => args.stream = true,
+                "--format" => args.format = value().map_err(expected)?.parse()?,
+                "--threads" => args.threads = value().map_err(expected)?.parse()?,
+                "--top" => args.top = Some(value().map_err(expected)?.parse()?),
+                "--map-size" => {
+                    let size = value().map_err(expected)?.parse()?;
+                    if size == 0 {
+                        bail!("`--map-size` needs at least one entry");
+                    }
+                    args.map_size = Some(size);
+                }
+                "--debug-dir" => args.debug_dir = Some(value().map_err(expected)?.into()),
+                "--cwd" => args.cwd = Some(value().map_err(expected)?.into()),
+                "--stdin" => args.stdin = Some(value().map_err(expected)?.into()),
+                "--stdout" => args.stdout = Some(value().map_err(expected)?.into()),
+                "--bin" | "--example" | "--test" | "--bench" => {
+                    let value = value().map_err(expected)?;
+                    args.target = Some(match name {
+                        "--bin" => Target::Bin(value.clone()),
+                        "--example" => Target::Example(value.clone()),
+                        "--test" => Target::Test(value.clone()),
+                        _ => Target::Bench(value.clone()),
+                    });
+                    args.cargo.push(name.to_string());
+                    args.cargo.push(value);
+                }
+                // the executable is found in the json messages of cargo.
+                "--message-format" => {
+                    bail!(
+                        "`--message-format` is not supported, cargo-trace reads the json messages"
+                    )
+                }
+                // cargo build flags that take a value.
+                "-p" | "--package" | "--exclude" | "-F" | "--features" | "--target"
+                | "--target-dir" | "--manifest-path" | "--profile" | "-j" | "--jobs"
+                | "--color" | "--config" | "--lockfile-path" | "-Z" => {
+                    let value = value().map_err(expected)?;
+                    args.cargo.push(name.to_string());
+                    args.cargo.push(value);
+                }
+                _ => args.cargo.push(arg.clone()),
+            }
+        }
+        // `cargo trace annotate <probes>` is short for `--format annotate`.
+        if positional.first().map(|arg| arg.as_str()) == Some("annotate") {
+            positional.remove(0);
+            args.format = Format::Annotate;
+        }
+        match positional.first().map(|arg| arg.as_str()) {
+            Some("diff") => {
+                if positional.len() != 3 {
+                    bail!("expected `cargo trace diff <before> <after>`");
+                }
+                args.mode = Mode::Diff {
+                    before: positional[1].clone().into(),
+                    after: positional[2].clone().into(),
+                };
+            }
+            Some("offcpu") => {
+                if positional.len() != 1 {
+                    bail!("`cargo trace offcpu` doesn't take probes");
+                }
+                args.mode = Mode::OffCpu;
+            }
+            Some("heap") => {
+                if positional.len() != 1 {
+                    bail!("`cargo trace heap` doesn't take probes");
+                }
+                args.mode = Mode::Heap;
+            }
+            Some("latency") => {
+                if positional.len() != 2 {
+                    bail!("expected `cargo trace latency <function>`");
+                }
+                if args.live {
+                    bail!("`cargo trace latency` doesn't support `--live`");
+                }
+                args.mode = Mode::Latency {
+                    function: positional[1].clone(),
+                };
+            }
+            Some("report") => {
+                if positional.len() != 2 {
+                    bail!("expected `cargo trace report <raw.profile>`");
+                }
+                args.mode = Mode::Report {
+                    profile: positional[1].clone().into(),
+                };
+            }
+            _ => {
+                // the weight is either part of the probe or the next argument.
+                for arg in positional {
+                    if arg.starts_with("weight=") {
+                        let probe = match args.probes.last_mut() {
+                            Some(probe) => probe,
+                            None => bail!("expected a probe before `{}`", arg),
+                        };
+                        probe.push(' ');
+                        probe.push_str(&arg);
+                    } else {
+                        args.probes.push(arg);
+                    }
+                }
+                for probe in &args.probes {
+                    split_weight(probe)?;
+                }
+            }
+        }
+        Ok(args)
+    }
+}
+
+/// Parses durations like `30s`, `500ms`, `5m` or `1h`, plain numbers are seconds.
+pub fn parse_duration(s: &str) -> Result<Duration> {
+    let i = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let n: u64 = s[..i].parse()?;
+    Ok(match &s[i..] {
+        "ms" => Duration::from_millis(n),
+        "" | "s" => Duration::from_secs(n),
+        "m" => Duration::from_secs(n * 60),
+        "h" => Duration::from_secs(n * 60 * 60),
+        unit => bail!("unsupported unit `{}`", unit),
+    })
+}
+
+fn expected(name: &str) -> anyhow::Error {
+    anyhow::anyhow!("expected a value for `{}`", name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &str) -> Args {
+        Args::parse(args.split_whitespace().map(|arg| arg.to_string())).unwrap()
+    }
+
+    #[test]
+    fn target_from_str() {
+        let args = parse("trace --release -p foo --test=integration profile:hz:99 -- --input a");
+        assert_eq!(args.probes, ["profile:hz:99"]);
+        assert_eq!(args.target, Some(Target::Test("integration".into())));
+        assert_eq!(
+            args.cargo,
+            ["--release", "-p", "foo", "--test", "integration"]
+        );
+        assert_eq!(args.program_args, ["--input", "a"]);
+    }
+
+    #[test]
+    fn forward_cargo_flags() {
+        let args = parse("-F feat --color always profile:hz:99");
+        assert_eq!(args.probes, ["profile:hz:99"]);
+        assert_eq!(args.cargo, ["-F", "feat", "--color", "always"]);
+    }
+
+    #[test]
+    fn pid_from_str() {
+        let args = parse("--pid 42 profile:hz:99 uprobe:libc:malloc");
+        assert_eq!(args.probes, ["profile:hz:99", "uprobe:libc:malloc"]);
+        assert_eq!(args.pid, Some(42));
+        assert!(args.cargo.is_empty());
+    }
+
+    #[test]
+    fn env_from_str() {
+        let args = parse("--env RUST_LOG=debug=info profile:hz:99");
+        assert_eq!(args.env, [("RUST_LOG".into(), "debug=info".into())]);
+    }
+
+    #[test]
+    fn format_from_str() {
+        assert_eq!(parse("profile:hz:99").format, Format::Flamegraph);
+        assert_eq!(parse("--format=pprof profile:hz:99").format, Format::Pprof);
+        assert_eq!(
+            parse("--format firefox profile:hz:99").format,
+            Format::Firefox
+        );
+        assert!(Args::parse(vec!["--format".into(), "svg".into()]).is_err());
+    }
+
+    #[test]
+    fn live_from_str() {
+        assert!(parse("--live profile:hz:99").live);
+        assert!(!parse("profile:hz:99").live);
+    }
+
+    #[test]
+    fn annotate_from_str() {
+        let args = parse("trace annotate --example profile profile:hz:99");
+        assert_eq!(args.format, Format::Annotate);
+        assert_eq!(args.probes, ["profile:hz:99"]);
+        assert_eq!(args.mode, Mode::Record);
+    }
+
+    #[test]
+    fn reject_message_format() {
+        let args = |args: &str| Args::parse(args.split_whitespace().map(|arg| arg.to_string()));
+        assert!(args("--message-format short profile:hz:99").is_err());
+        assert!(args("--message-format=json profile:hz:99").is_err());
+    }
+
+    #[test]
+    fn diff_from_str() {
+        let args = parse("trace diff before.txt after.txt --top 5");
+        assert_eq!(
+            args.mode,
+            Mode::Diff {
+                before: "before.txt".into(),
+                after: "after.txt".into(),
+            }
+        );
+        assert_eq!(args.top, Some(5));
+        assert!(args.probes.is_empty());
+        assert!(Args::parse(vec!["diff".into(), "before.txt".into()]).is_err());
+    }
+
+    #[test]
+    fn report_from_str() {
+        let args = parse("report raw.profile --debug-dir /debug --format pprof");
+        assert_eq!(
+            args.mode,
+            Mode::Report {
+                profile: "raw.profile".into(),
+            }
+        );
+        assert_eq!(args.debug_dir, Some("/debug".into()));
+        assert_eq!(args.format, Format::Pprof);
+    }
+
+    #[test]
+    fn threads_from_str() {
+        assert_eq!(parse("profile:hz:99").threads, ThreadMode::Merge);
+        assert_eq!(
+            parse("--threads split profile:hz:99").threads,
+            ThreadMode::Split
+        );
+    }
+
+    #[test]
+    fn map_size_from_str() {
+        assert_eq!(
+            parse("--map-size 16384 profile:hz:99").map_size,
+            Some(16384)
+        );
+        assert_eq!(parse("profile:hz:99").map_size, None);
+        assert!(Args::parse(vec!["--map-size".into(), "-1".into()]).is_err());
+        assert!(Args::parse(vec!["--map-size".into(), "0".into()]).is_err());
+    }
+
+    #[test]
+    fn stream_from_str() {
+        assert!(parse("--stream profile:hz:99").stream);
+        assert!(!parse("profile:hz:99").stream);
+    }
+
+    #[test]
+    fn offcpu_from_str() {
+        let args = parse("trace offcpu --example blocking");
+        assert_eq!(args.mode, Mode::OffCpu);
+        assert_eq!(args.target, Some(Target::Example("blocking".into())));
+        assert!(args.probes.is_empty());
+        assert!(Args::parse(vec!["offcpu".into(), "profile:hz:99".into()]).is_err());
+    }
+
+    #[test]
+    fn heap_from_str() {
+        let args = parse("trace heap --example malloc");
+        assert_eq!(args.mode, Mode::Heap);
+        assert_eq!(args.target, Some(Target::Example("malloc".into())));
+        assert!(Args::parse(vec!["heap".into(), "profile:hz:99".into()]).is_err());
+    }
+
+    #[test]
+    fn latency_from_str() {
+        let args = parse("trace latency my_crate::handler --by-stack");
+        assert_eq!(
+            args.mode,
+            Mode::Latency {
+                function: "my_crate::handler".into()
+            }
+        );
+        assert!(args.by_stack);
+        assert!(!parse("latency my_crate::handler").by_stack);
+        assert!(Args::parse(vec!["latency".into()]).is_err());
+    }
+
+    #[test]
+    fn weight_from_str() {
+        let args = parse("uprobe:libc:malloc weight=arg0 uretprobe:libc:read weight=retval");
+        assert_eq!(
+            args.probes,
+            [
+                "uprobe:libc:malloc weight=arg0",
+                "uretprobe:libc:read weight=retval"
+            ]
+        );
+        let (probe, weight) = split_weight(&args.probes[0]).unwrap();
+        assert_eq!(probe, "uprobe:libc:malloc");
+        assert_eq!(weight, Weight::Arg(0));
+        assert_eq!(weight.register(), 1);
+        let args = Args::parse(vec!["kprobe:vfs_read weight=arg2".into()]).unwrap();
+        assert_eq!(split_weight(&args.probes[0]).unwrap().1, Weight::Arg(2));
+        assert_eq!(split_weight("profile:hz:99").unwrap().1, Weight::Count);
+        assert_eq!(Weight::Retval.register(), 7);
+        assert!(Args::parse(vec!["weight=arg0".into()]).is_err());
+        assert!(Args::parse(vec!["uprobe:libc:malloc".into(), "weight=arg6".into()]).is_err());
+        assert!(split_weight("uprobe:libc:malloc size").is_err());
+        assert!(Args::parse(vec!["uprobe:libc:read weight=retval".into()]).is_err());
+        assert!(Args::parse(vec!["kretprobe:vfs_read weight=arg2".into()]).is_err());
+    }
+
+    #[test]
+    fn unit_of_each_probe() {
+        let args = parse("profile:hz:99 uprobe:libc:malloc weight=arg0");
+        let units: Vec<_> = args
+            .probes
+            .iter()
+            .map(|probe| split_weight(probe).unwrap().1.unit())
+            .collect();
+        assert_eq!(units, [Unit::Samples, Unit::Weight]);
+    }
+
+    #[test]
+    fn duration_from_str() {
+        assert_eq!(parse_duration("30").unwrap(), Duration::from_secs(30));
+        assert_eq!(parse_duration("30s").unwrap(), Duration::from_secs(30));
+        assert_eq!(parse_duration("500ms").unwrap(), Duration::from_millis(500));
+        assert_eq!(parse_duration("2m").unwrap(), Duration::from_secs(120));
+        assert!(parse_duration("2d").is_err());
+        assert!(parse_duration("s").is_err());
+    }
+}
@@ -0,0 +1,108 @@
+use crate::args::{Args, Target};
+use anyhow::{bail, Result};
+use serde::Deserialize;
+use std::io::{BufRead, BufReader};
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+
+#[derive(Deserialize)]
+#[serde(tag = "reason", rename_all = "kebab-case")]
+enum Message {
+    CompilerArtifact(Artifact),
+    #[serde(other)]
+    Other,
+}
+
+#[derive(Deserialize)]
+struct Artifact {
+    target: ArtifactTarget,
+    executable: Option<PathBuf>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ArtifactTarget {
+    name: String,
+    kind: Vec<String>,
+}
+
+impl ArtifactTarget {
+    fn matches(&self, target: &Target) -> bool {
+        self.name == target.name() && self.kind.iter().any(|kind| kind == target.kind())
+    }
+}
+
+/// Builds the program and returns the path of the executable to trace.
+///
+/// Test and bench harnesses have a hash in their file name, so executables are
+/// discovered from the json messages emitted by cargo instead of being guessed.
+pub fn build(args: &Args) -> Result<PathBuf> {
+    let mut child = Command::new("cargo")
+        .arg("build")
+        .arg("--message-format=json-render-diagnostics")
+        .args(&args.cargo)
+        .stdout(Stdio::piped())
+        .spawn()?;
+    let mut executables = vec![];
+    for line in BufReader::new(child.stdout.take().unwrap()).lines() {
+        if let Message::CompilerArtifact(artifact) = serde_json::from_str(&line?)? {
+            if let Some(executable) = artifact.executable {
+                executables.push((artifact.target, executable));
+            }
+        }
+    }
+    let status = child.wait()?;
+    if !status.success() {
+        std::process::exit(status.code().unwrap_or(1));
+    }
+    select(args.target.as_ref(), executables)
+}
+
+fn select(target: Option<&Target>, executables: Vec<(ArtifactTarget, PathBuf)>) -> Result<PathBuf> {
+    let mut candidates: Vec<_> = executables
+        .into_iter()
+        .filter(|(artifact, _)| {
+            target
+                .map(|target| artifact.matches(target))
+                .unwrap_or(true)
+        })
+        .collect();
+    match candidates.len() {
+        0 => bail!("no executable found to trace"),
+        1 => Ok(candidates.remove(0).1),
+        _ => {
+            let names = candidates
+                .iter()
+                .map(|(artifact, _)| format!("{} `{}`", artifact.kind.join(","), artifact.name))
+                .collect::<Vec<_>>()
+                .join(", ");
+            bail!(
+                "found multiple executables ({}), select one with `--bin`, `--example`, `--test` or `--bench`",
+                names
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn artifact(kind: &str, name: &str) -> (ArtifactTarget, PathBuf) {
+        let target = ArtifactTarget {
+            name: name.into(),
+            kind: vec![kind.into()],
+        };
+        (target, format!("/target/{}-{}", kind, name).into())
+    }
+
+    #[test]
+    fn select_executable() {
+        let executables = || vec![artifact("bin", "app"), artifact("test", "app")];
+        let path = select(Some(&Target::Test("app".into())), executables()).unwrap();
+        assert_eq!(path, PathBuf::from("/target/test-app"));
+        let path = select(Some(&Target::Bin("app".into())), executables()).unwrap();
+        assert_eq!(path, PathBuf::from("/target/bin-app"));
+        assert!(select(None, executables()).is_err());
+        assert!(select(Some(&Target::Example("app".into())), executables()).is_err());
+    }
+}
@@ -0,0 +1,140 @@
+use anyhow::Result;
+use inferno::differential;
+use inferno::flamegraph::{self, Options};
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+
+/// Change of the share of samples containing a function.
+#[derive(Debug, PartialEq)]
+pub struct Change {
+    pub function: String,
+    pub before: f64,
+    pub after: f64,
+}
+
+impl Change {
+    pub fn delta(&self) -> f64 {
+        self.after - self.before
+    }
+}
+
+/// Writes a differential flamegraph of two collapsed stack files to `flamegraph-diff.svg`
+/// and prints the functions whose share of samples changed the most.
+pub fn diff(before: &Path, after: &Path, top: usize) -> Result<()> {
+    let mut collapsed = vec![];
+    differential::from_files(
+        differential::Options::default(),
+        before,
+        after,
+        &mut collapsed,
+    )?;
+    let collapsed = String::from_utf8(collapsed)?;
+    let mut options = Options::default();
+    options.title = format!("{} vs {}", before.display(), after.display());
+    let f = File::create("flamegraph-diff.svg")?;
+    flamegraph::from_lines(&mut options, collapsed.lines(), f)?;
+
+    let before = shares(BufReader::new(File::open(before)?))?;
+    let after = shares(BufReader::new(File::open(after)?))?;
+    let changes = changes(&before, &after);
+    print_changes(
+        "grew",
+        changes.iter().rev().filter(|c| c.delta() > 0.0),
+        top,
+    );
+    print_changes("shrank", changes.iter().filter(|c| c.delta() < 0.0), top);
+    Ok(())
+}
+
+fn print_changes<'a>(title: &str, changes: impl Iterator<Item = &'a Change>, top: usize) {
+    println!("{}:", title);
+    println!("{:>8} {:>8} {:>8}  function", "before", "after", "delta");
+    for change in changes.take(top) {
+        println!(
+            "{:>7.2}% {:>7.2}% {:>+7.2}%  {}",
+            change.before * 100.0,
+            change.after * 100.0,
+            change.delta() * 100.0,
+            change.function
+        );
+    }
+    println!();
+}
+
+/// Returns the share of samples each function appears in, recursive calls are
+/// only counted once per stack.
+fn shares(collapsed: impl BufRead) -> Result<HashMap<String, f64>> {
+    let mut counts = HashMap::<String, u64>::new();
+    let mut total = 0;
+    for line in collapsed.lines() {
+        let line = line?;
+        let mut split = line.trim_end().rsplitn(2, ' ');
+        let count = split.next().and_then(|count| count.parse::<u64>().ok());
+        let (stack, count) = match (split.next(), count) {
+            (Some(stack), Some(count)) => (stack, count),
+            _ => continue,
+        };
+        total += count;
+        let mut seen = HashSet::new();
+        for function in stack.split(';') {
+            if seen.insert(function) {
+                *counts.entry(function.to_string()).or_default() += count;
+            }
+        }
+    }
+    // a profile without samples has no shares.
+    if total == 0 {
+        return Ok(HashMap::new());
+    }
+    Ok(counts
+        .into_iter()
+        .map(|(function, count)| (function, count as f64 / total as f64))
+        .collect())
+}
+
+/// Returns the changes of all functions sorted by delta, biggest decrease first.
+fn changes(before: &HashMap<String, f64>, after: &HashMap<String, f64>) -> Vec<Change> {
+    let functions: HashSet<_> = before.keys().chain(after.keys()).collect();
+    let mut changes: Vec<_> = functions
+        .into_iter()
+        .map(|function| Change {
+            function: function.clone(),
+            before: before.get(function).copied().unwrap_or_default(),
+            after: after.get(function).copied().unwrap_or_default(),
+        })
+        .collect();
+    changes.sort_by(|a, b| {
+        a.delta()
+            .partial_cmp(&b.delta())
+            .unwrap()
+            .then_with(|| a.function.cmp(&b.function))
+    });
+    changes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn share_changes() {
+        let before = "main;a;b 2\nmain;c 2\n";
+        let after = "main;a;a 1\nmain;c 3\n";
+        let before = shares(before.as_bytes()).unwrap();
+        let after = shares(after.as_bytes()).unwrap();
+        assert_eq!(before["main"], 1.0);
+        assert_eq!(after["a"], 0.25);
+        assert!(shares("main;a 0\n".as_bytes()).unwrap().is_empty());
+        let changes = changes(&before, &after);
+        let deltas: Vec<_> = changes
+            .iter()
+            .map(|change| (change.function.as_str(), change.delta()))
+            .collect();
+        assert_eq!(
+            deltas,
+            [("b", -0.5), ("a", -0.25), ("main", 0.0), ("c", 0.25)]
+        );
+    }
+}
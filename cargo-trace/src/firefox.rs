@@ -0,0 +1,269 @@
+//! Writer for the gecko profile format understood by the
+//! [Firefox Profiler](https://profiler.firefox.com).
+use crate::frames::{Frame, FrameTable};
+use crate::process::Processes;
+use crate::sample::{Thread, Unit};
+use anyhow::Result;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::fs::File;
+
+/// Interval between two samples in milliseconds.
+///
+/// Samples aggregated in the kernel are laid out one interval apart instead of at
+/// the time they were taken, streamed samples are placed at the time they were taken.
+const INTERVAL: f64 = 1.0;
+
+/// Gecko formatted thread, every thread has it's own frame, stack and string tables.
+#[derive(Default)]
+struct GeckoThread {
+    strings: Vec<String>,
+    string_indices: HashMap<String, usize>,
+    frames: Vec<Value>,
+    frame_indices: HashMap<usize, usize>,
+    stacks: Vec<Value>,
+    stack_indices: HashMap<(Option<usize>, usize), usize>,
+    samples: Vec<Value>,
+}
+
+/// Type of the sample weights understood by the profiler, off cpu time is shown as a
+/// duration in milliseconds.
+fn weight_type(unit: Unit) -> &'static str {
+    match unit {
+        Unit::Samples | Unit::Weight => "samples",
+        Unit::Nanoseconds => "tracing-ms",
+        Unit::Bytes => "bytes",
+    }
+}
+
+/// Converts a count to the unit of `weight_type`.
+fn weight(unit: Unit, count: u64) -> Value {
+    match unit {
+        Unit::Nanoseconds => json!(count as f64 / 1e6),
+        _ => json!(count),
+    }
+}
+
+impl GeckoThread {
+    fn string(&mut self, s: String) -> usize {
+        if let Some(index) = self.string_indices.get(&s) {
+            return *index;
+        }
+        self.strings.push(s.clone());
+        self.string_indices.insert(s, self.strings.len() - 1);
+        self.strings.len() - 1
+    }
+
+    fn frame(&mut self, index: usize, frame: &Frame) -> usize {
+        if let Some(index) = self.frame_indices.get(&index) {
+            return *index;
+        }
+        // the profiler extracts the file from locations like `name (file:line:column)`.
+        let location = match frame.file.as_ref() {
+            Some(file) => format!(
+                "{} ({}:{}:{})",
+                frame.name,
+                file,
+                frame.line.unwrap_or_default(),
+                frame.column.unwrap_or_default()
+            ),
+            None => frame.name.clone(),
+        };
+        let location = self.string(location);
+        // location, relevantForJS, innerWindowID, implementation, line, column, category, subcategory
+        self.frames.push(json!([
+            location,
+            false,
+            0,
+            null,
+            frame.line,
+            frame.column,
+            0,
+            0
+        ]));
+        self.frame_indices.insert(index, self.frames.len() - 1);
+        self.frames.len() - 1
+    }
+
+    fn stack(&mut self, prefix: Option<usize>, frame: usize) -> usize {
+        if let Some(index) = self.stack_indices.get(&(prefix, frame)) {
+            return *index;
+        }
+        self.stacks.push(json!([prefix, frame]));
+        self.stack_indices
+            .insert((prefix, frame), self.stacks.len() - 1);
+        self.stacks.len() - 1
+    }
+
+    fn into_json(self, thread: &Thread, unit: Unit) -> Value {
+        json!({
+            "name": thread.name,
+            "processType": "default",
+            "processName": thread.process,
+            "registerTime": 0,
+            "unregisterTime": null,
+            "pid": thread.pid,
+            "tid": thread.tid,
+            "markers": {
+                "schema": {
+                    "name": 0,
+                    "startTime": 1,
+                    "endTime": 2,
+                    "phase": 3,
+                    "category": 4,
+                    "data": 5,
+                },
+                "data": [],
+            },
+            "samples": {
+                "schema": { "stack": 0, "time": 1, "eventDelay": 2, "weight": 3 },
+                "weightType": weight_type(unit),
+                "data": self.samples,
+            },
+            "frameTable": {
+                "schema": {
+                    "location": 0,
+                    "relevantForJS": 1,
+                    "innerWindowID": 2,
+                    "implementation": 3,
+                    "line": 4,
+                    "column": 5,
+                    "category": 6,
+                    "subcategory": 7,
+                },
+                "data": self.frames,
+            },
+            "stackTable": {
+                "schema": { "prefix": 0, "frame": 1 },
+                "data": self.stacks,
+            },
+            "stringTable": self.strings,
+        })
+    }
+}
+
+/// Writes a gecko profile with one track per thread, the tracks are grouped by process.
+pub fn write_firefox(
+    processes: &Processes,
+    threads: &[Thread],
+    unit: Unit,
+    title: &str,
+    path: &str,
+) -> Result<()> {
+    let profile = gecko_profile(processes, threads, unit, title)?;
+    serde_json::to_writer(File::create(path)?, &profile)?;
+    Ok(())
+}
+
+fn gecko_profile(
+    processes: &Processes,
+    threads: &[Thread],
+    unit: Unit,
+    title: &str,
+) -> Result<Value> {
+    let mut frames = FrameTable::new(processes);
+    let mut gecko_threads = Vec::with_capacity(threads.len());
+    // the timeline starts with the first streamed sample.
+    let start = threads
+        .iter()
+        .flat_map(|thread| &thread.samples)
+        .flat_map(|sample| &sample.events)
+        .map(|(time, _)| *time)
+        .min()
+        .unwrap_or_default();
+    for thread in threads {
+        let mut gecko = GeckoThread::default();
+        let mut samples = vec![];
+        let mut time = 0.0;
+        for sample in &thread.samples {
+            let mut prefix = None;
+            for index in frames.stack(sample)? {
+                let frame = gecko.frame(index, &frames.frames()[index]);
+                prefix = Some(gecko.stack(prefix, frame));
+            }
+            for (at, weight) in &sample.events {
+                samples.push(((at - start) as f64 / 1e6, prefix, *weight));
+            }
+            if sample.events.is_empty() {
+                samples.push((time, prefix, sample.count));
+                time += INTERVAL;
+            }
+        }
+        samples.sort_by(|(a, _, _), (b, _, _)| a.total_cmp(b));
+        for (time, prefix, count) in samples {
+            gecko
+                .samples
+                .push(json!([prefix, time, 0, weight(unit, count)]));
+        }
+        gecko_threads.push(gecko.into_json(thread, unit));
+    }
+    Ok(json!({
+        "meta": {
+            "version": 24,
+            "interval": INTERVAL,
+            "startTime": 0,
+            "shutdownTime": null,
+            "processType": 0,
+            "stackwalk": 1,
+            "debug": false,
+            "product": title,
+            "categories": [
+                { "name": "Other", "color": "grey", "subcategories": ["Other"] },
+            ],
+            "markerSchema": [],
+        },
+        "libs": [],
+        "pages": [],
+        "threads": gecko_threads,
+        "processes": [],
+        "pausedRanges": [],
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sample::Sample;
+    use bpf::utils::BinaryInfo;
+
+    #[test]
+    fn weight_samples() -> Result<()> {
+        let processes = Processes::new(BinaryInfo::from_binaries(1, vec![]));
+        let sample = |symbol: &str, count| Sample {
+            kernel_stack: vec![symbol.into(), "entry_SYSCALL_64".into()],
+            ..Sample::new(0, 1, 1, 0, count)
+        };
+        let streamed = Sample {
+            events: vec![(2_000_000, 1_000_000), (1_000_000, 3_000_000)],
+            ..sample("schedule", 4_000_000)
+        };
+        let thread = Thread {
+            pid: 1,
+            tid: 1,
+            name: "main".into(),
+            process: "main".into(),
+            samples: vec![sample("do_sys_poll", 1_500_000), streamed],
+        };
+        let profile = gecko_profile(&processes, &[thread], Unit::Nanoseconds, "offcpu")?;
+        let thread = &profile["threads"][0];
+        assert_eq!(
+            thread["samples"]["schema"],
+            json!({ "stack": 0, "time": 1, "eventDelay": 2, "weight": 3 })
+        );
+        assert_eq!(thread["samples"]["weightType"], "tracing-ms");
+        // each sample is emitted once with it's count as the weight.
+        assert_eq!(
+            thread["samples"]["data"],
+            json!([[1, 0.0, 0, 1.5], [2, 0.0, 0, 3.0], [2, 1.0, 0, 1.0]])
+        );
+        assert_eq!(
+            thread["stackTable"]["data"],
+            json!([[null, 0], [0, 1], [0, 2]])
+        );
+        assert_eq!(
+            thread["stringTable"],
+            json!(["entry_SYSCALL_64_[k]", "do_sys_poll_[k]", "schedule_[k]"])
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,112 @@
+use crate::process::Processes;
+use crate::sample::{Sample, TRUNCATED};
+use anyhow::Result;
+use serde::Serialize;
+use std::collections::HashMap;
+
+/// Symbolized stack frame.
+#[derive(Clone, Debug, Eq, Hash, PartialEq, Serialize)]
+pub struct Frame {
+    pub name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub file: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub line: Option<u32>,
+    #[serde(rename = "col", skip_serializing_if = "Option::is_none")]
+    pub column: Option<u32>,
+}
+
+/// Deduplicates frames, each address of a process is only symbolized once.
+pub struct FrameTable<'a> {
+    processes: &'a Processes,
+    frames: Vec<Frame>,
+    indices: HashMap<Frame, usize>,
+    addresses: HashMap<(u32, u32, usize, bool), Vec<usize>>,
+}
+
+impl<'a> FrameTable<'a> {
+    pub fn new(processes: &'a Processes) -> Self {
+        Self {
+            processes,
+            frames: vec![],
+            indices: HashMap::new(),
+            addresses: HashMap::new(),
+        }
+    }
+
+    pub fn frames(&self) -> &[Frame] {
+        &self.frames
+    }
+
+    /// Returns the indices of the frames at `ip` in the process `pid` after it's `exec`th
+    /// exec, the inlined functions first. Empty if `ip` can't be symbolized.
+    ///
+    /// `ip` is a return address unless it's the `leaf` of the stack.
+    pub fn frames_at(&mut self, pid: u32, exec: u32, ip: usize, leaf: bool) -> Result<&[usize]> {
+        let key = (pid, exec, ip, leaf);
+        if !self.addresses.contains_key(&key) {
+            let info = self.processes.get(pid, exec);
+            let mut indices = vec![];
+            for resolved in info.resolve_frames(ip, leaf)? {
+                let location = resolved.location.as_ref();
+                let frame = Frame {
+                    name: resolved.name,
+                    file: location
+                        .and_then(|loc| loc.file)
+                        .map(|file| file.to_string()),
+                    line: location.and_then(|loc| loc.line),
+                    column: location.and_then(|loc| loc.column),
+                };
+                indices.push(self.insert(frame));
+            }
+            self.addresses.insert(key, indices);
+        }
+        Ok(&self.addresses[&key])
+    }
+
+    fn insert(&mut self, frame: Frame) -> usize {
+        let frames = &mut self.frames;
+        *self.indices.entry(frame).or_insert_with_key(|frame| {
+            frames.push(frame.clone());
+            frames.len() - 1
+        })
+    }
+
+    /// Symbolizes the kernel and user stack of a sample, returning the frame indices
+    /// root first.
+    ///
+    /// Like in the flamegraph the user stack is truncated at the first address that
+    /// can't be symbolized, stacks the unwinder gave up on get a `[truncated]` root.
+    pub fn stack(&mut self, sample: &Sample) -> Result<Vec<usize>> {
+        let mut frames = Vec::with_capacity(sample.kernel_stack.len() + sample.stack.len());
+        for name in sample.kernel_frames() {
+            frames.push(self.insert(Frame {
+                name,
+                file: None,
+                line: None,
+                column: None,
+            }));
+        }
+        for (i, ip) in sample.stack.iter().enumerate() {
+            let ip = ip.get() as usize;
+            if ip == 0 {
+                break;
+            }
+            let resolved = self.frames_at(sample.pid, sample.exec, ip, i == 0)?;
+            if resolved.is_empty() {
+                break;
+            }
+            frames.extend_from_slice(resolved);
+        }
+        if sample.truncated {
+            frames.push(self.insert(Frame {
+                name: TRUNCATED.to_string(),
+                file: None,
+                line: None,
+                column: None,
+            }));
+        }
+        frames.reverse();
+        Ok(frames)
+    }
+}
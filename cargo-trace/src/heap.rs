@@ -0,0 +1,164 @@
+//! Heap profiling by tracing the allocator.
+//!
+//! The size of an allocation is recorded when the allocator is entered and the stack
+//! when it returns. Allocations are tracked by address until they are freed, so the
+//! allocations that are still live at exit can be grouped by the stack that made them.
+use crate::sample::Sample;
+use anyhow::{bail, Result};
+use bpf::utils::{BinaryInfo, Elf};
+use bpf::{Bpf, BpfBuilder, Probe, U32, U64};
+use std::collections::HashMap;
+use zerocopy::{AsBytes, FromBytes, Unaligned};
+
+#[derive(Clone, Copy, AsBytes, FromBytes, Unaligned)]
+#[repr(C)]
+pub struct AllocationKey {
+    pid: U32,
+    _pad: U32,
+    ptr: U64,
+}
+
+#[derive(Clone, Copy, AsBytes, FromBytes, Unaligned)]
+#[repr(C)]
+pub struct Allocation {
+    size: U64,
+    stack: U64,
+}
+
+/// Function, entry run when it is called and entry run when it returns.
+type Function = (&'static str, &'static str, Option<&'static str>);
+
+static LIBC: &[Function] = &[
+    ("malloc", "malloc_entry", Some("alloc_return")),
+    ("calloc", "calloc_entry", Some("alloc_return")),
+    ("realloc", "realloc_entry", Some("alloc_return")),
+    ("free", "free_entry", None),
+];
+
+/// The allocator shims of rust programs, they call the global allocator which isn't
+/// necessarily malloc.
+static RUST: &[Function] = &[
+    (
+        "__rust_alloc",
+        "rust_alloc_entry",
+        Some("rust_alloc_return"),
+    ),
+    (
+        "__rust_alloc_zeroed",
+        "rust_alloc_entry",
+        Some("rust_alloc_return"),
+    ),
+    (
+        "__rust_realloc",
+        "rust_realloc_entry",
+        Some("rust_alloc_return"),
+    ),
+    (
+        "__rust_dealloc",
+        "rust_dealloc_entry",
+        Some("rust_dealloc_return"),
+    ),
+];
+
+/// Attaches to the allocator functions of libc and of the program.
+pub fn attach(builder: &mut BpfBuilder, info: &BinaryInfo) -> Result<()> {
+    let mut attached = attach_functions(builder, info.elf(), RUST)?;
+    if let Some(libc) = info.library("libc") {
+        attached |= attach_functions(builder, &libc.elf, LIBC)?;
+    }
+    if !attached {
+        bail!("couldn't find an allocator in {}", info.path().display());
+    }
+    Ok(())
+}
+
+fn attach_functions(builder: &mut BpfBuilder, elf: &Elf, functions: &[Function]) -> Result<bool> {
+    let mut attached = false;
+    for (name, entry, ret) in functions {
+        let symbol = if let Some(symbol) = elf.find_symbol(name) {
+            symbol
+        } else {
+            continue;
+        };
+        let path = Some(elf.path().to_owned());
+        let probe = Probe::Uprobe {
+            path: path.clone(),
+            symbol: symbol.to_string(),
+            offset: 0,
+        };
+        builder.attach_probe(probe, entry)?;
+        if let Some(ret) = ret {
+            let probe = Probe::Uretprobe {
+                path,
+                symbol: symbol.to_string(),
+            };
+            builder.attach_probe(probe, ret)?;
+        }
+        attached = true;
+    }
+    Ok(attached)
+}
+
+/// Returns the allocations that weren't freed as samples of the probe `1`, the
+/// allocated bytes are the samples of the probe `0`.
+pub fn live_samples(bpf: &mut Bpf, samples: &[Sample]) -> Result<Vec<Sample>> {
+    let mut live = HashMap::<u64, u64>::new();
+    for (_, allocation) in bpf.hash_map::<AllocationKey, Allocation>("LIVE")?.iter() {
+        *live.entry(allocation.stack.get()).or_default() += allocation.size.get();
+    }
+    Ok(group_by_stack(samples, &live))
+}
+
+fn group_by_stack(samples: &[Sample], live: &HashMap<u64, u64>) -> Vec<Sample> {
+    samples
+        .iter()
+        .filter(|sample| sample.probe == 0)
+        .filter_map(|sample| {
+            let size = *live.get(&stack_hash(sample))?;
+            let mut sample = sample.clone();
+            // the memory is live at exit, not when it was allocated.
+            sample.probe = 1;
+            sample.count = size;
+            sample.events.clear();
+            Some(sample)
+        })
+        .collect()
+}
+
+/// FNV-1a hash of the pid, tid and user stack, must match the hash of the bpf program.
+fn stack_hash(sample: &Sample) -> u64 {
+    let mut hash = 0xcbf2_9ce4_8422_2325u64;
+    let id = sample.pid as u64 | (sample.tid as u64) << 32;
+    hash = (hash ^ id).wrapping_mul(0x100_0000_01b3);
+    for ip in sample.stack.iter() {
+        hash = (hash ^ ip.get()).wrapping_mul(0x100_0000_01b3);
+    }
+    hash
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn group_live_allocations_by_stack() {
+        let sample = |tid, ip, count| Sample::new(0, 1, tid, ip, count);
+        let samples = [sample(1, 0x10, 96), sample(1, 0x20, 64), sample(2, 0x10, 8)];
+        let mut live = HashMap::new();
+        live.insert(stack_hash(&samples[0]), 32);
+        live.insert(stack_hash(&samples[2]), 8);
+        let live = group_by_stack(&samples, &live);
+        let live: Vec<_> = live
+            .iter()
+            .map(|sample| {
+                (
+                    sample.probe,
+                    sample.tid,
+                    sample.stack[0].get(),
+                    sample.count,
+                )
+            })
+            .collect();
+        assert_eq!(live, [(1, 1, 0x10, 32), (1, 2, 0x10, 8)]);
+    }
+}
@@ -0,0 +1,233 @@
+//! Latency histograms of the calls of a function.
+//!
+//! The bpf program counts the calls by the log2 of their latency in nanoseconds, with
+//! `--by-stack` the stack of the caller is recorded as a sample of the probe with the
+//! index of the bucket.
+use crate::process::Processes;
+use crate::sample::Sample;
+use anyhow::{anyhow, Result};
+use bpf::utils::BinaryInfo;
+use bpf::{Bpf, BpfBuilder, Probe};
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+/// Number of buckets, bucket `i` counts the calls that took less than `2^i` nanoseconds.
+const BUCKETS: usize = 65;
+
+/// Width of the bars of the histogram.
+const WIDTH: usize = 40;
+
+/// Attaches to the entry and return of the function `function` of the program, the
+/// function is either a symbol or a rust path like `my_crate::handler`.
+pub fn attach(builder: &mut BpfBuilder, info: &BinaryInfo, function: &str) -> Result<()> {
+    let elf = info.elf();
+    let symbol = elf
+        .find_symbol(function)
+        .or_else(|| elf.find_function(function))
+        .ok_or_else(|| anyhow!("couldn't find `{}` in {}", function, info.path().display()))?;
+    let path = Some(elf.path().to_owned());
+    let probe = Probe::Uprobe {
+        path: path.clone(),
+        symbol: symbol.to_string(),
+        offset: 0,
+    };
+    builder.attach_probe(probe, "latency_entry")?;
+    let probe = Probe::Uretprobe {
+        path,
+        symbol: symbol.to_string(),
+    };
+    builder.attach_probe(probe, "latency_return")?;
+    builder.attach_probe_str("tracepoint:sched:sched_process_exit", "sched_process_exit")?;
+    Ok(())
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Histogram {
+    buckets: [u64; BUCKETS],
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Self {
+            buckets: [0; BUCKETS],
+        }
+    }
+}
+
+impl Histogram {
+    /// Reads the histogram of all calls from the bpf program.
+    pub fn read(bpf: &mut Bpf) -> Result<Self> {
+        let mut histogram = Self::default();
+        for (i, bucket) in histogram.buckets.iter_mut().enumerate() {
+            *bucket = bpf.percpu_sum("LATENCY", i as _)?;
+        }
+        Ok(histogram)
+    }
+
+    pub fn calls(&self) -> u64 {
+        self.buckets.iter().sum()
+    }
+
+    /// Upper bound of the latency of `p` percent of the calls.
+    pub fn percentile(&self, p: f64) -> Duration {
+        let calls = (self.calls() as f64 * p / 100.0).ceil() as u64;
+        let mut sum = 0;
+        for (i, bucket) in self.buckets.iter().enumerate() {
+            sum += bucket;
+            if sum >= calls {
+                return nanos(upper_bound(i));
+            }
+        }
+        nanos(upper_bound(BUCKETS - 1))
+    }
+
+    /// Renders the buckets between the shortest and the longest call.
+    pub fn render(&self) -> String {
+        let first = self.buckets.iter().position(|n| *n > 0);
+        let last = self.buckets.iter().rposition(|n| *n > 0);
+        let (first, last) = match (first, last) {
+            (Some(first), Some(last)) => (first, last),
+            _ => return "no calls\n".to_string(),
+        };
+        let max = self.buckets.iter().max().copied().unwrap_or_default();
+        let mut out = format!("{:>24} : {:<10} distribution\n", "ns", "count");
+        for i in first..=last {
+            let lower = if i == 0 { 0 } else { upper_bound(i - 1) };
+            let range = format!("{} -> {}", lower, upper_bound(i) - 1);
+            let bar = (self.buckets[i] * WIDTH as u64 / max) as usize;
+            out.push_str(&format!(
+                "{:>24} : {:<10} |{:<width$}|\n",
+                range,
+                self.buckets[i],
+                "*".repeat(bar),
+                width = WIDTH
+            ));
+        }
+        out
+    }
+}
+
+/// Latency in nanoseconds that the calls counted by `bucket` took less than, the bound
+/// of the last bucket doesn't fit into a `u64`.
+fn upper_bound(bucket: usize) -> u128 {
+    1 << bucket
+}
+
+fn nanos(nanos: u128) -> Duration {
+    Duration::new((nanos / 1_000_000_000) as _, (nanos % 1_000_000_000) as _)
+}
+
+/// Prints the histogram and percentiles of the calls of `function`.
+pub fn print_histogram(function: &str, histogram: &Histogram) {
+    println!("{} ({} calls)", function, histogram.calls());
+    print!("{}", histogram.render());
+    if histogram.calls() > 0 {
+        println!(
+            "p50 < {:?}, p90 < {:?}, p99 < {:?}, max < {:?}",
+            histogram.percentile(50.0),
+            histogram.percentile(90.0),
+            histogram.percentile(99.0),
+            histogram.percentile(100.0)
+        );
+    }
+}
+
+/// Prints the histograms of the `top` callers with the most calls.
+pub fn print_by_stack(processes: &Processes, samples: &[Sample], top: usize) -> Result<()> {
+    for ((pid, exec, stack), histogram) in by_stack(samples).into_iter().take(top) {
+        let info = processes.get(pid, exec);
+        println!("\ncalled from ({} calls):", histogram.calls());
+        // the stack is taken after the function returned, so it starts at a return address.
+        for ip in stack {
+            let frames = info.resolve_frames(ip as _, false)?;
+            if frames.is_empty() {
+                println!("    0x{:x}", ip);
+            }
+            for frame in frames {
+                let inlined = if frame.inlined { " (inlined)" } else { "" };
+                println!("    {}{}", frame.name, inlined);
+            }
+        }
+        print!("{}", histogram.render());
+    }
+    Ok(())
+}
+
+/// Pid, number of execs and stack of a caller.
+type Caller = (u32, u32, Vec<u64>);
+
+/// Histograms by process and stack of the caller, the callers with the most calls first.
+fn by_stack(samples: &[Sample]) -> Vec<(Caller, Histogram)> {
+    let mut stacks = BTreeMap::<_, Histogram>::new();
+    for sample in samples {
+        let stack = sample
+            .stack
+            .iter()
+            .map(|ip| ip.get())
+            .take_while(|ip| *ip != 0)
+            .collect();
+        let histogram = stacks.entry((sample.pid, sample.exec, stack)).or_default();
+        if let Some(bucket) = histogram.buckets.get_mut(sample.probe as usize) {
+            *bucket += sample.count;
+        }
+    }
+    let mut stacks: Vec<_> = stacks.into_iter().collect();
+    stacks.sort_by_key(|(_, histogram)| std::cmp::Reverse(histogram.calls()));
+    stacks
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn histogram(calls: &[(usize, u64)]) -> Histogram {
+        let mut histogram = Histogram::default();
+        for (bucket, n) in calls {
+            histogram.buckets[*bucket] = *n;
+        }
+        histogram
+    }
+
+    #[test]
+    fn histogram_percentiles() {
+        let histogram = histogram(&[(10, 50), (11, 40), (20, 10)]);
+        assert_eq!(histogram.calls(), 100);
+        assert_eq!(histogram.percentile(50.0), Duration::from_nanos(1024));
+        assert_eq!(histogram.percentile(90.0), Duration::from_nanos(2048));
+        assert_eq!(histogram.percentile(99.0), Duration::from_nanos(1 << 20));
+        let rendered = histogram.render();
+        let lines: Vec<_> = rendered.lines().collect();
+        assert_eq!(lines.len(), 12);
+        assert!(lines[1].trim_start().starts_with("512 -> 1023 : 50 "));
+        assert!(lines[1].ends_with(&format!("|{}|", "*".repeat(WIDTH))));
+        assert_eq!(Histogram::default().render(), "no calls\n");
+    }
+
+    #[test]
+    fn longest_calls() {
+        let histogram = histogram(&[(0, 1), (64, 1)]);
+        assert_eq!(
+            histogram.percentile(100.0),
+            Duration::from_nanos(u64::MAX) + Duration::from_nanos(1)
+        );
+        let rendered = histogram.render();
+        let lines: Vec<_> = rendered.lines().collect();
+        assert!(lines[1].trim_start().starts_with("0 -> 0 : 1 "));
+        assert!(lines[65].trim_start().starts_with(&format!(
+            "{} -> {} : 1 ",
+            1u64 << 63,
+            u64::MAX
+        )));
+    }
+
+    #[test]
+    fn histograms_by_stack() {
+        let sample = |bucket, ip, count| Sample::new(bucket, 1, 1, ip, count);
+        let samples = [sample(3, 0x10, 1), sample(4, 0x20, 2), sample(5, 0x20, 3)];
+        let stacks = by_stack(&samples);
+        assert_eq!(stacks.len(), 2);
+        assert_eq!(stacks[0].0, (1, 0, vec![0x20]));
+        assert_eq!(stacks[0].1, histogram(&[(4, 2), (5, 3)]));
+        assert_eq!(stacks[1].1, histogram(&[(3, 1)]));
+    }
+}
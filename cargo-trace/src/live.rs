@@ -0,0 +1,266 @@
+//! Top like terminal view of the hottest functions while the program is running.
+use crate::process::Processes;
+use crate::sample::{Sample, Unit};
+use anyhow::Result;
+use bpf::utils::BinaryInfo;
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, HashSet};
+use std::io::{Error, Write};
+use std::time::{Duration, Instant};
+
+/// Interval between two refreshes of the view.
+const REFRESH: Duration = Duration::from_secs(1);
+
+/// Puts the terminal in non canonical mode without echo, so that single key
+/// presses can be read without blocking. ctrl-c still sends a `SIGINT`.
+struct RawMode(libc::termios);
+
+impl RawMode {
+    fn enable() -> Result<Self> {
+        unsafe {
+            let mut termios = std::mem::zeroed();
+            if libc::tcgetattr(libc::STDIN_FILENO, &mut termios) < 0 {
+                return Err(Error::last_os_error().into());
+            }
+            let original = termios;
+            termios.c_lflag &= !(libc::ICANON | libc::ECHO);
+            termios.c_cc[libc::VMIN] = 0;
+            termios.c_cc[libc::VTIME] = 0;
+            if libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &termios) < 0 {
+                return Err(Error::last_os_error().into());
+            }
+            // switch to the alternate screen and hide the cursor.
+            print!("\x1b[?1049h\x1b[?25l");
+            Ok(Self(original))
+        }
+    }
+
+    fn read_key(&self) -> Option<u8> {
+        let mut key = 0u8;
+        let n = unsafe { libc::read(libc::STDIN_FILENO, &mut key as *mut u8 as *mut _, 1) };
+        if n == 1 {
+            Some(key)
+        } else {
+            None
+        }
+    }
+
+    fn rows(&self) -> usize {
+        let mut size: libc::winsize = unsafe { std::mem::zeroed() };
+        if unsafe { libc::ioctl(libc::STDOUT_FILENO, libc::TIOCGWINSZ, &mut size) } < 0
+            || size.ws_row == 0
+        {
+            return 24;
+        }
+        size.ws_row as usize
+    }
+}
+
+impl Drop for RawMode {
+    fn drop(&mut self) {
+        print!("\x1b[?25h\x1b[?1049l");
+        let _ = std::io::stdout().flush();
+        unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &self.0) };
+    }
+}
+
+pub struct LiveView {
+    terminal: RawMode,
+    /// Unit of the samples of each probe.
+    units: Vec<Unit>,
+    /// Show inclusive instead of self samples.
+    inclusive: bool,
+    /// Counts at the last reset.
+    baseline: HashMap<Key, u64>,
+    reset: bool,
+    symbols: HashMap<(u32, usize, bool), Vec<String>>,
+    last_render: Option<Instant>,
+}
+
+impl LiveView {
+    pub fn new(units: Vec<Unit>) -> Result<Self> {
+        Ok(Self {
+            terminal: RawMode::enable()?,
+            units,
+            inclusive: false,
+            baseline: HashMap::new(),
+            reset: false,
+            symbols: HashMap::new(),
+            last_render: None,
+        })
+    }
+
+    /// Handles pending key presses, returns `false` when the view was closed.
+    ///
+    /// `s` shows self samples, `i` inclusive samples, `r` resets the counters and `q` quits.
+    pub fn handle_keys(&mut self) -> bool {
+        while let Some(key) = self.terminal.read_key() {
+            match key {
+                b'q' => return false,
+                b's' => self.inclusive = false,
+                b'i' => self.inclusive = true,
+                b'r' => self.reset = true,
+                _ => continue,
+            }
+            self.last_render = None;
+        }
+        true
+    }
+
+    pub fn needs_render(&self) -> bool {
+        self.last_render
+            .map(|last| last.elapsed() >= REFRESH)
+            .unwrap_or(true)
+    }
+
+    pub fn render(
+        &mut self,
+        processes: &Processes,
+        probes: &[String],
+        samples: &[Sample],
+    ) -> Result<()> {
+        self.last_render = Some(Instant::now());
+        if self.reset {
+            self.reset = false;
+            self.baseline = samples
+                .iter()
+                .map(|sample| (key(sample), sample.count))
+                .collect();
+        }
+        let rows = self.terminal.rows().saturating_sub(2);
+        let rows_per_probe = rows / probes.len().max(1);
+        let mut out = String::new();
+        out.push_str("\x1b[H\x1b[2J");
+        out.push_str(&format!(
+            "cargo-trace: {}, [s]elf [i]nclusive [r]eset [q]uit\n",
+            if self.inclusive { "inclusive" } else { "self" },
+        ));
+        for (i, probe) in probes.iter().enumerate() {
+            let mut stacks = vec![];
+            for sample in samples.iter().filter(|sample| sample.probe == i as u32) {
+                let baseline = self.baseline.get(&key(sample));
+                let count = sample
+                    .count
+                    .saturating_sub(baseline.copied().unwrap_or_default());
+                if count == 0 {
+                    continue;
+                }
+                let mut stack: Vec<_> = sample.kernel_frames().collect();
+                let info = processes.get(sample.pid, sample.exec);
+                stack.extend(self.symbolize(info, &ips(&sample.stack))?);
+                stacks.push((stack, count));
+            }
+            let total: u64 = stacks.iter().map(|(_, count)| count).sum();
+            let unit = self.units[i];
+            out.push_str(&format!("\n{} ({} {})\n", probe, total, unit));
+            out.push_str(&format!("{:>8}  function\n", "%"));
+            let functions = aggregate(stacks, self.inclusive);
+            for (function, count) in functions.iter().take(rows_per_probe.saturating_sub(3)) {
+                out.push_str(&format!(
+                    "{:>7.2}%  {}\n",
+                    *count as f64 * 100.0 / total as f64,
+                    function
+                ));
+            }
+        }
+        let mut stdout = std::io::stdout();
+        stdout.write_all(out.as_bytes())?;
+        stdout.flush()?;
+        Ok(())
+    }
+
+    /// Symbolizes a stack leaf first including inlined functions, unknown addresses are
+    /// kept as hex.
+    fn symbolize(&mut self, info: &BinaryInfo, ips: &[u64]) -> Result<Vec<String>> {
+        let mut stack = Vec::with_capacity(ips.len());
+        for (i, ip) in ips.iter().enumerate() {
+            let ip = *ip as usize;
+            let symbols = match self.symbols.entry((info.pid(), ip, i == 0)) {
+                Entry::Occupied(entry) => entry.into_mut(),
+                Entry::Vacant(entry) => {
+                    let frames = info.resolve_frames(ip, i == 0)?;
+                    entry.insert(frames.into_iter().map(|frame| frame.name).collect())
+                }
+            };
+            if symbols.is_empty() {
+                stack.push(format!("0x{:x}", ip));
+            } else {
+                stack.extend(symbols.iter().cloned());
+            }
+        }
+        Ok(stack)
+    }
+}
+
+/// Identifies a stack by probe, pid, tid, kernel stack and user stack.
+type Key = (u32, u32, u32, u32, Vec<String>, Vec<u64>);
+
+fn key(sample: &Sample) -> Key {
+    (
+        sample.probe,
+        sample.pid,
+        sample.tid,
+        sample.exec,
+        sample.kernel_stack.clone(),
+        ips(&sample.stack),
+    )
+}
+
+fn ips(stack: &[bpf::U64]) -> Vec<u64> {
+    stack
+        .iter()
+        .map(|ip| ip.get())
+        .take_while(|ip| *ip != 0)
+        .collect()
+}
+
+/// Sums the samples per function, either only counting the leaf or every function
+/// on the stack once. Sorted by count, highest first.
+fn aggregate(stacks: Vec<(Vec<String>, u64)>, inclusive: bool) -> Vec<(String, u64)> {
+    let mut counts = HashMap::<String, u64>::new();
+    for (stack, count) in stacks {
+        if inclusive {
+            let functions: HashSet<_> = stack.into_iter().collect();
+            for function in functions {
+                *counts.entry(function).or_default() += count;
+            }
+        } else if let Some(leaf) = stack.into_iter().next() {
+            *counts.entry(leaf).or_default() += count;
+        }
+    }
+    let mut counts: Vec<_> = counts.into_iter().collect();
+    counts.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+    counts
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn aggregate_self_and_inclusive() {
+        let stack = |s: &str| s.split(';').map(|f| f.to_string()).collect::<Vec<_>>();
+        let stacks = || {
+            vec![
+                (stack("b;a;main"), 3),
+                (stack("c;main"), 2),
+                (stack("a;a;main"), 1),
+            ]
+        };
+        let functions = aggregate(stacks(), false);
+        assert_eq!(
+            functions,
+            [("b".into(), 3), ("c".into(), 2), ("a".into(), 1)]
+        );
+        let functions = aggregate(stacks(), true);
+        assert_eq!(
+            functions,
+            [
+                ("main".into(), 6),
+                ("a".into(), 4),
+                ("b".into(), 3),
+                ("c".into(), 2)
+            ]
+        );
+    }
+}
@@ -1,34 +1,124 @@
-use anyhow::Result;
-use bpf::utils::{ehframe, sudo, BinaryInfo};
-use bpf::{BpfBuilder, Probe, ProgramType, I64, U32, U64};
-use cargo_subcommand::Subcommand;
+use crate::args::{split_weight, Args, Format, Mode, ThreadMode, Weight};
+use crate::latency::Histogram;
+use crate::live::LiveView;
+use crate::process::Processes;
+use crate::raw::RawProfile;
+use crate::sample::{Sample, Thread, Unit, MAX_STACK_DEPTH, TRUNCATED};
+use crate::stream::Stream;
+use anyhow::{anyhow, bail, Result};
+use bpf::utils::{ehframe, sudo, task, BinaryInfo, KernelSymbolTable};
+use bpf::{Bpf, BpfBuilder, Probe, ProgramType, I32, I64, U32, U64};
+use inferno::flamegraph::color::{MultiPalette, Palette};
 use inferno::flamegraph::{self, Options};
+use std::collections::BTreeMap;
 use std::fs::{File, OpenOptions};
 use std::io::{BufRead, BufReader, Write};
+use std::os::unix::process::CommandExt;
+use std::path::Path;
 use std::process::Command;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 use zerocopy::{AsBytes, FromBytes, Unaligned};
 
+mod annotate;
+mod args;
+mod cargo;
+mod diff;
+mod firefox;
+mod frames;
+mod heap;
+mod latency;
+mod live;
+mod pprof;
+mod process;
+mod raw;
+mod sample;
+mod speedscope;
+mod stream;
+
+/// Environment variable used to pass the built executable to the escalated process.
+const ARTIFACT_VAR: &str = "CARGO_TRACE_ARTIFACT";
+
 static PROBE: &[u8] = include_bytes!(concat!(
     env!("OUT_DIR"),
     "/target/bpf/programs/cargo-trace-probe/cargo-trace-probe.elf",
 ));
 
+/// Maximum number of unwind table rows of all traced processes.
+const EHFRAME_ENTRIES: usize = 0xff_ffff;
+
+/// Number of functions listed in reports unless `--top` is given.
+const DEFAULT_TOP: usize = 10;
+
+/// Number of distinct stacks that can be recorded unless `--map-size` is given.
+const DEFAULT_MAP_SIZE: u32 = 1024;
+
+/// Indices of the counters of dropped samples in `STATS`.
+const DROPPED_SAMPLES: u32 = 0;
+const DROPPED_WEIGHT: u32 = 1;
+const DROPPED_KERNEL_STACKS: u32 = 2;
+const DROPPED_ALLOCATIONS: u32 = 3;
+
+/// Number of probes supported by the bpf program.
+const MAX_PROBES: usize = 8;
+static PERF_EVENT_ENTRIES: [&str; MAX_PROBES] = [
+    "perf_event_0",
+    "perf_event_1",
+    "perf_event_2",
+    "perf_event_3",
+    "perf_event_4",
+    "perf_event_5",
+    "perf_event_6",
+    "perf_event_7",
+];
+static KPROBE_ENTRIES: [&str; MAX_PROBES] = [
+    "kprobe_0", "kprobe_1", "kprobe_2", "kprobe_3", "kprobe_4", "kprobe_5", "kprobe_6", "kprobe_7",
+];
+
+#[derive(Clone, Copy, Eq, Hash, PartialEq, AsBytes, FromBytes, Unaligned)]
+#[repr(C)]
+pub struct StackKey {
+    probe: U32,
+    pid: U32,
+    tid: U32,
+    kernel_stack: I32,
+    truncated: U32,
+    exec: U32,
+    stack: [U64; MAX_STACK_DEPTH],
+}
+
+#[derive(Clone, Copy, AsBytes, FromBytes, Unaligned)]
+#[repr(C)]
+pub struct Process {
+    start: U32,
+    len: U32,
+    exec: U32,
+    _pad: U32,
+}
+
 #[derive(Clone, Copy, AsBytes, FromBytes, Unaligned)]
 #[repr(C)]
 pub struct Instruction {
-    op: U64,
+    op: U32,
+    /// Threshold of the `rip & 15` of a PLT entry for `rsp`, offset of the saved `rbp`
+    /// below the cfa for `rip`.
+    arg: U32,
     offset: I64,
 }
 
 impl From<ehframe::Instruction> for Instruction {
     fn from(ins: ehframe::Instruction) -> Self {
         Self {
-            op: U64::new(match (ins.op(), ins.reg()) {
+            op: U32::new(match (ins.op(), ins.reg()) {
                 (ehframe::Op::CfaOffset, None) => 1,
                 (ehframe::Op::Register, Some(ehframe::Reg::Rip)) => 2,
                 (ehframe::Op::Register, Some(ehframe::Reg::Rsp)) => 3,
+                (ehframe::Op::Register, Some(ehframe::Reg::Rbp)) => 4,
+                (ehframe::Op::Plt, Some(ehframe::Reg::Rsp)) => 5,
                 _ => 0,
             }),
+            arg: U32::new(ins.arg().unwrap_or_default() as _),
             offset: I64::new(ins.offset().unwrap_or_default()),
         }
     }
@@ -36,117 +126,676 @@ impl From<ehframe::Instruction> for Instruction {
 
 fn main() -> Result<()> {
     env_logger::init();
-    let args = std::env::args();
-    let cmd = Subcommand::new(args, "trace", |_, _| Ok(true))?;
-    if sudo::check() == sudo::RunningAs::User {
-        let status = Command::new("cargo")
-            .arg("build")
-            .args(cmd.args())
-            .spawn()?
-            .wait()?;
-        if !status.success() {
-            std::process::exit(status.code().unwrap());
-        }
+    let args = Args::from_env()?;
+    if let Mode::Diff { before, after } = &args.mode {
+        return diff::diff(before, after, args.top.unwrap_or(DEFAULT_TOP));
+    }
+    if let Mode::Report { profile } = &args.mode {
+        let profile = RawProfile::read(profile)?;
+        let processes = profile.processes(args.debug_dir.as_deref())?;
+        return write_profiles(
+            &processes,
+            &args,
+            &profile.probes,
+            &profile.units,
+            &profile.threads,
+            &profile.samples(),
+        );
+    }
+    // the executable is built before escalating privileges and passed on to the
+    // escalated process. when attaching to a running process there is nothing to build.
+    if args.pid.is_none() && std::env::var_os(ARTIFACT_VAR).is_none() {
+        let path = cargo::build(&args)?;
+        std::env::set_var(ARTIFACT_VAR, path);
     }
     let uid = unsafe { libc::getuid() };
-    sudo::with_env(&["RUST_LOG"]).unwrap();
-
-    let mut info = BinaryInfo::from_cargo_subcommand(&cmd)?;
-
-    // TODO more convenience:
-    // uprobes: find path from libname
-    // tracepoint: convert to kprobes on syscalls
-    let mut probe: Probe = cmd.cmd().parse()?;
-    let entry = match probe.prog_type() {
-        ProgramType::Kprobe => "kprobe",
-        ProgramType::PerfEvent => "perf_event",
-        _ => return Err(anyhow::anyhow!("unsupported probe {}", probe)),
+    sudo::with_env(&["RUST_LOG", ARTIFACT_VAR]).unwrap();
+
+    let info = if let Some(pid) = args.pid {
+        BinaryInfo::from_pid(pid)?
+    } else {
+        let mut program = Command::new(std::env::var_os(ARTIFACT_VAR).unwrap());
+        program.args(&args.program_args);
+        program.envs(args.env.iter().cloned());
+        if let Some(cwd) = args.cwd.as_ref() {
+            program.current_dir(cwd);
+        }
+        let interactive = args.stdin.is_none() && unsafe { libc::isatty(0) } == 1;
+        if let Some(stdin) = args.stdin.as_ref() {
+            program.stdin(File::open(stdin)?);
+        }
+        if let Some(stdout) = args.stdout.as_ref() {
+            program.stdout(File::create(stdout)?);
+        }
+        // put the program in it's own process group, so that it keeps running when
+        // the session is stopped with ctrl-c. a background group is stopped when it
+        // reads from the terminal, so programs reading the terminal stay in the
+        // foreground and receive ctrl-c as well.
+        if !interactive {
+            unsafe {
+                program.pre_exec(|| {
+                    if libc::setpgid(0, 0) < 0 {
+                        return Err(std::io::Error::last_os_error());
+                    }
+                    Ok(())
+                });
+            }
+        }
+        BinaryInfo::spawn(program)?
     };
-    log::debug!("setting default path to {}", info.path().display());
-    probe.set_default_path(info.path());
+
+    // kernel addresses are hidden from unprivileged users, so the symbols are loaded
+    // while we're still root.
+    let ksyms = KernelSymbolTable::load()?;
     let mut builder = BpfBuilder::new(PROBE)?;
-    if let ProgramType::PerfEvent = probe.prog_type() {
-        // without this we will get kernel regs instead of user regs.
-        builder.set_child_pid(info.pid());
-    }
-    builder.attach_probe(probe, entry)?;
+    let map_size = args.map_size.unwrap_or(DEFAULT_MAP_SIZE);
+    builder.set_max_entries("USER_STACK", map_size)?;
+    builder.set_max_entries("KERNEL_STACKS", map_size)?;
+    let (probes, units) = if args.mode == Mode::OffCpu {
+        // the stack of a blocked thread is recorded when it is switched back in.
+        builder.attach_probe_str("tracepoint:sched:sched_switch", "sched_switch")?;
+        let symbol = ksyms
+            .function("finish_task_switch")
+            .ok_or_else(|| anyhow!("couldn't find `finish_task_switch` in /proc/kallsyms"))?;
+        let probe = Probe::Kprobe {
+            symbol: symbol.to_string(),
+            offset: 0,
+        };
+        builder.attach_probe(probe, "finish_task_switch")?;
+        (vec!["offcpu".to_string()], vec![Unit::Nanoseconds])
+    } else if args.mode == Mode::Heap {
+        heap::attach(&mut builder, &info)?;
+        let probes = vec!["allocated".to_string(), "live".to_string()];
+        (probes, vec![Unit::Bytes; 2])
+    } else if let Mode::Latency { function } = &args.mode {
+        latency::attach(&mut builder, &info, function)?;
+        (vec![function.clone()], vec![Unit::Samples])
+    } else {
+        // TODO more convenience:
+        // tracepoint: convert to kprobes on syscalls
+        if args.probes.is_empty() {
+            bail!("expected a probe like `profile:hz:99`");
+        }
+        if args.probes.len() > MAX_PROBES {
+            bail!("at most {} probes are supported", MAX_PROBES);
+        }
+        for (i, probe) in args.probes.iter().enumerate() {
+            let (probe, weight) = split_weight(probe)?;
+            let mut probe: Probe = probe.parse()?;
+            let entry = match probe.prog_type() {
+                ProgramType::Kprobe => KPROBE_ENTRIES[i],
+                ProgramType::PerfEvent if weight != Weight::Count => {
+                    bail!("{} can't be weighted, only kprobes and uprobes can", probe)
+                }
+                ProgramType::PerfEvent => {
+                    // without this we will get kernel regs instead of user regs.
+                    builder.set_child_pid(info.pid());
+                    PERF_EVENT_ENTRIES[i]
+                }
+                _ => return Err(anyhow!("unsupported probe {}", probe)),
+            };
+            resolve_library(&info, &mut probe);
+            log::debug!("setting default path to {}", info.path().display());
+            probe.set_default_path(info.path());
+            builder.attach_probe(probe, entry)?;
+        }
+        let units = args
+            .probes
+            .iter()
+            .map(|probe| Ok(split_weight(probe)?.1.unit()))
+            .collect::<Result<_>>()?;
+        (args.probes.clone(), units)
+    };
+    // follow the processes spawned by the program.
+    builder.attach_probe_str("tracepoint:task:task_newtask", "task_newtask")?;
+    builder.attach_probe_str("tracepoint:sched:sched_process_exec", "sched_process_exec")?;
+    // and the libraries it loads after it started.
+    attach_loader(&mut builder, &info)?;
     let mut bpf = builder.load()?;
     log::debug!("loaded bpf program");
+    // a program can only unwind a few frames, deeper stacks are unwound by tail calls.
+    bpf.set_tail_call("UNWIND_PERF_EVENT", 0, "unwind_perf_event")?;
+    bpf.set_tail_call("UNWIND_KPROBE", 0, "unwind_kprobe")?;
+    let mut weights = bpf.array::<U32>("WEIGHTS")?;
+    for (i, probe) in args.probes.iter().enumerate() {
+        let (_, weight) = split_weight(probe)?;
+        weights.insert(&U32::new(i as _), &U32::new(weight.register()))?;
+    }
+    if args.by_stack {
+        let mut latency_stacks = bpf.array::<U32>("LATENCY_STACKS")?;
+        latency_stacks.insert(&U32::new(0), &U32::new(1))?;
+    }
+    let mut stream = if args.stream {
+        Some(Stream::new(&mut bpf)?)
+    } else {
+        None
+    };
 
-    let mut i = 0;
+    let mut processes = Processes::new(info);
+    let mut rows = load_unwind_table(&mut bpf, processes.root(), 0, 0)?;
+
+    let running = Arc::new(AtomicBool::new(true));
+    let handler = running.clone();
+    ctrlc::set_handler(move || handler.store(false, Ordering::SeqCst))?;
+    let deadline = args.duration.map(|duration| Instant::now() + duration);
+
+    let mut live = if args.live {
+        Some(LiveView::new(units.clone())?)
+    } else {
+        None
+    };
+
+    // thread names are read while the program is running, threads that exit in
+    // between are named after their id.
+    let mut names = BTreeMap::new();
+
+    log::debug!("running program");
+    processes.root_mut().cont()?;
+    while running.load(Ordering::SeqCst) && !processes.root_mut().has_exited()? {
+        follow_processes(&mut bpf, &mut processes, &mut rows)?;
+        reload_libraries(&mut bpf, &mut processes, &mut rows)?;
+        for (_, info) in processes.iter() {
+            if let Ok(threads) = task::thread_names(info.pid()) {
+                names.extend(threads);
+            }
+        }
+        if deadline
+            .map(|deadline| Instant::now() >= deadline)
+            .unwrap_or_default()
+        {
+            break;
+        }
+        if let Some(live) = live.as_mut() {
+            if !live.handle_keys() {
+                break;
+            }
+            if live.needs_render() {
+                let samples = read_samples(&mut bpf, &ksyms, &args.mode, stream.as_ref())?;
+                live.render(&processes, &probes, &samples)?;
+            }
+        }
+        match stream.as_mut() {
+            Some(stream) => stream.poll(Duration::from_millis(100))?,
+            None => std::thread::sleep(Duration::from_millis(100)),
+        }
+    }
+    // restore the terminal before anything else is printed.
+    drop(live);
+    bpf.detach();
+    if let Some(stream) = stream.as_mut() {
+        stream.poll(Duration::from_millis(0))?;
+        stream.log_summary();
+    }
+    let program = processes.root_mut();
+    if !program.has_exited()? {
+        if args.kill {
+            log::debug!("killing program");
+            program.kill()?;
+        } else {
+            log::info!("program {} is still running", program.pid());
+        }
+    }
+
+    unsafe { libc::setuid(uid) };
+    let samples = read_samples(&mut bpf, &ksyms, &args.mode, stream.as_ref())?;
+    report_dropped(&mut bpf, stream.as_ref(), &units, map_size)?;
+    if let Mode::Latency { function } = &args.mode {
+        latency::print_histogram(function, &Histogram::read(&mut bpf)?);
+        if args.by_stack {
+            let top = args.top.unwrap_or(DEFAULT_TOP);
+            latency::print_by_stack(&processes, &samples, top)?;
+        }
+        return Ok(());
+    }
+    write_profiles(&processes, &args, &probes, &units, &names, &samples)
+}
+
+/// Writes the unwind table of a process after it's `exec`th exec to the rows starting at
+/// `start`, returns the number of rows.
+fn load_unwind_table(bpf: &mut Bpf, info: &BinaryInfo, exec: u32, start: u32) -> Result<u32> {
+    let mut i = start;
     for binary in info.iter() {
         let table = binary.elf.unwind_table()?;
+        if !table.unsupported.is_empty() {
+            eprintln!(
+                "warning: {}: {} rows with {} unsupported cfa expressions can't be unwound",
+                binary.elf.path().display(),
+                table.unsupported.values().sum::<usize>(),
+                table.unsupported.len()
+            );
+        }
+        for (expr, rows) in &table.unsupported {
+            log::debug!(
+                "{}: {} rows with unsupported cfa expression {}",
+                binary.elf.path().display(),
+                rows,
+                expr
+            );
+        }
+        if i as usize + table.rows.len() > EHFRAME_ENTRIES {
+            bail!("unwind tables of more than {} rows", EHFRAME_ENTRIES);
+        }
         for row in table.rows.iter() {
             let addr = binary.start_addr + row.start_address;
             let mut pc = bpf.array::<U64>("PC")?;
-            pc.insert(&U32::new(i as _), &U64::new(addr as _))?;
+            pc.insert(&U32::new(i), &U64::new(addr as _))?;
 
+            let mut ins = Instruction::from(row.rip);
+            ins.arg = U32::new(saved_rbp(&row.rbp));
             let mut rip = bpf.array::<Instruction>("RIP")?;
-            rip.insert(&U32::new(i as _), &row.rip.into())?;
+            rip.insert(&U32::new(i), &ins)?;
 
             let mut rsp = bpf.array::<Instruction>("RSP")?;
-            rsp.insert(&U32::new(i as _), &row.rsp.into())?;
+            rsp.insert(&U32::new(i), &row.rsp.into())?;
 
             i += 1;
         }
     }
-    let mut len = bpf.array::<U32>("CONFIG")?;
-    len.insert(&U32::new(0), &U32::new(i as _))?;
-    len.insert(&U32::new(1), &U32::new(info.pid()))?;
+    let process = Process {
+        start: U32::new(start),
+        len: U32::new(i - start),
+        exec: U32::new(exec),
+        _pad: U32::new(0),
+    };
+    let mut traced = bpf.hash_map::<U32, Process>("PROCESSES")?;
+    traced.insert(&U32::new(info.pid()), &process)?;
+    Ok(i - start)
+}
 
-    log::debug!("running program");
-    info.cont()?;
+/// Returns the offset of the saved `rbp` below the cfa, or `0` when `rbp` keeps its value.
+/// Functions only ever save `rbp` on the stack, so it doesn't need a table of its own.
+fn saved_rbp(rbp: &ehframe::Instruction) -> u32 {
+    match (rbp.op(), rbp.offset()) {
+        (ehframe::Op::CfaOffset, Some(offset)) if offset < 0 => -offset as u32,
+        _ => 0,
+    }
+}
 
-    unsafe { libc::setuid(uid) };
-    let user_stack = bpf.hash_map::<[U64; 48], U32>("USER_STACK")?;
+/// Attaches to `_dl_debug_state` of the dynamic loader, which is called when libraries
+/// are loaded with `dlopen` or unloaded with `dlclose`.
+fn attach_loader(builder: &mut BpfBuilder, info: &BinaryInfo) -> Result<()> {
+    let loader = if let Some(loader) = info.library("ld") {
+        loader
+    } else {
+        log::debug!("{} isn't dynamically linked", info.path().display());
+        return Ok(());
+    };
+    let symbol = if let Some(symbol) = loader.elf.find_symbol("_dl_debug_state") {
+        symbol
+    } else {
+        log::warn!(
+            "couldn't find `_dl_debug_state` in {}, libraries loaded with dlopen won't be unwound",
+            loader.elf.path().display()
+        );
+        return Ok(());
+    };
+    let probe = Probe::Uprobe {
+        path: Some(loader.elf.path().to_owned()),
+        symbol: symbol.to_string(),
+        offset: 0,
+    };
+    builder.attach_probe(probe, "dl_debug_state")
+}
 
-    write_flamegraph(&info, user_stack.iter(), cmd.cmd().to_string())?;
+/// Reloads the binaries and unwind tables of the processes that loaded or unloaded
+/// libraries.
+///
+/// The new unwind table is written after the rows in use, the process is unwound with
+/// the old table until the new one is complete.
+fn reload_libraries(bpf: &mut Bpf, processes: &mut Processes, rows: &mut u32) -> Result<()> {
+    let pids: Vec<_> = bpf.hash_map::<U32, U32>("RELOAD")?.keys().collect();
+    for pid in pids {
+        // removed before reloading, so that libraries loaded in between aren't missed.
+        bpf.hash_map::<U32, U32>("RELOAD")?.remove(&pid)?;
+        let exec = match bpf.hash_map::<U32, Process>("PROCESSES")?.get(&pid)? {
+            Some(process) => process.exec.get(),
+            None => continue,
+        };
+        // the binaries of a process that called exec since are kept as they are.
+        let info = if let Some(info) = processes.get_mut(pid.get(), exec) {
+            info
+        } else {
+            continue;
+        };
+        match info.reload() {
+            Ok(true) => {}
+            Ok(false) => continue,
+            Err(err) => {
+                log::debug!("couldn't reload binaries of {}: {}", pid.get(), err);
+                continue;
+            }
+        }
+        match load_unwind_table(bpf, info, exec, *rows) {
+            Ok(len) => *rows += len,
+            Err(err) => log::warn!("couldn't reload unwind table of {}: {}", pid.get(), err),
+        }
+    }
+    Ok(())
+}
 
+/// Loads the binaries of the processes spawned by the traced processes and the
+/// binaries and unwind tables of processes that called exec.
+///
+/// The unwind tables of processes that exited are not reused, unwinding stops when
+/// all rows are used.
+fn follow_processes(bpf: &mut Bpf, processes: &mut Processes, rows: &mut u32) -> Result<()> {
+    let traced: Vec<_> = bpf.hash_map::<U32, Process>("PROCESSES")?.iter().collect();
+    for (pid, process) in traced {
+        let exec = process.exec.get();
+        if processes.contains(pid.get(), exec) {
+            continue;
+        }
+        // processes can exit before we get to them.
+        if task::tgid(pid.get()).ok() != Some(pid.get()) {
+            bpf.hash_map::<U32, Process>("PROCESSES")?.remove(&pid)?;
+            continue;
+        }
+        let info = match BinaryInfo::from_pid(pid.get()) {
+            Ok(info) => info,
+            Err(err) => {
+                log::debug!("couldn't load binaries of {}: {}", pid.get(), err);
+                continue;
+            }
+        };
+        // forked children share the unwind table of their parent until they call exec.
+        if process.len.get() == 0 {
+            match load_unwind_table(bpf, &info, exec, *rows) {
+                Ok(len) => *rows += len,
+                Err(err) => {
+                    log::warn!("stopped following {}: {}", pid.get(), err);
+                    bpf.hash_map::<U32, Process>("PROCESSES")?.remove(&pid)?;
+                    continue;
+                }
+            }
+        }
+        log::debug!("following process {} after {} execs", pid.get(), exec);
+        processes.insert(exec, info);
+    }
     Ok(())
 }
 
+/// Reads the sampled stacks, in heap mode followed by the allocations that are still live.
+///
+/// When samples are streamed they are counted in user space instead of in `USER_STACK`
+/// and keep the time they were taken.
+fn read_samples(
+    bpf: &mut Bpf,
+    ksyms: &KernelSymbolTable,
+    mode: &Mode,
+    stream: Option<&Stream>,
+) -> Result<Vec<Sample>> {
+    let user_stack: Vec<_> = match stream {
+        Some(stream) => stream.stacks(),
+        None => bpf
+            .hash_map::<StackKey, U64>("USER_STACK")?
+            .iter()
+            .map(|(key, count)| (key, count, vec![]))
+            .collect(),
+    };
+    let kernel_stacks = bpf.stack_trace("KERNEL_STACKS")?;
+    let mut samples = Vec::with_capacity(user_stack.len());
+    for (key, count, events) in user_stack {
+        let mut kernel_stack = vec![];
+        if key.kernel_stack.get() >= 0 {
+            if let Some(frames) = kernel_stacks.raw_stack_trace(key.kernel_stack.get() as _)? {
+                for ip in frames.iter() {
+                    kernel_stack.push(ksyms.symbol(ip as _).0.to_string());
+                }
+            }
+        }
+        samples.push(Sample {
+            probe: key.probe.get(),
+            pid: key.pid.get(),
+            tid: key.tid.get(),
+            exec: key.exec.get(),
+            kernel_stack,
+            stack: key.stack,
+            truncated: key.truncated.get() != 0,
+            count: count.get(),
+            events,
+        });
+    }
+    if *mode == Mode::Heap {
+        let live = heap::live_samples(bpf, &samples)?;
+        samples.extend(live);
+    }
+    Ok(samples)
+}
+
+/// Warns about the samples, kernel stacks and live allocations that were dropped because
+/// a map or the buffers of a stream were full.
+fn report_dropped(
+    bpf: &mut Bpf,
+    stream: Option<&Stream>,
+    units: &[Unit],
+    map_size: u32,
+) -> Result<()> {
+    if let Some(lost) = stream.map(|stream| stream.lost()).filter(|lost| *lost > 0) {
+        eprintln!(
+            "warning: lost {} samples because the stream couldn't keep up",
+            lost
+        );
+    }
+    let samples = bpf.percpu_sum("STATS", DROPPED_SAMPLES)?;
+    if samples > 0 {
+        let weight = bpf.percpu_sum("STATS", DROPPED_WEIGHT)?;
+        // the weights of probes with different units don't add up.
+        let weight = match units {
+            [unit, rest @ ..] if rest.iter().all(|other| other == unit) => {
+                format!(" ({} {})", weight, unit)
+            }
+            _ => String::new(),
+        };
+        eprintln!(
+            "warning: dropped {} samples{} because the stack map of {} entries was full, \
+             increase it with `--map-size`",
+            samples, weight, map_size
+        );
+    }
+    let stacks = bpf.percpu_sum("STATS", DROPPED_KERNEL_STACKS)?;
+    if stacks > 0 {
+        eprintln!(
+            "warning: dropped the kernel stack of {} samples because the kernel stack map of \
+             {} entries was full, increase it with `--map-size`",
+            stacks, map_size
+        );
+    }
+    let allocations = bpf.percpu_sum("STATS", DROPPED_ALLOCATIONS)?;
+    if allocations > 0 {
+        eprintln!(
+            "warning: {} allocations are missing from the live memory because too many \
+             allocations were live",
+            allocations
+        );
+    }
+    Ok(())
+}
+
+/// Writes the samples of each probe in the requested format.
+fn write_profiles(
+    processes: &Processes,
+    args: &Args,
+    probes: &[String],
+    units: &[Unit],
+    names: &BTreeMap<u32, String>,
+    samples: &[Sample],
+) -> Result<()> {
+    if args.format == Format::Raw {
+        // symbolization is deferred, so all probes are kept in a single file.
+        let profile = RawProfile::new(processes, probes, units, names, samples);
+        return profile.write(Path::new("raw.profile"));
+    }
+    for (i, probe) in probes.iter().enumerate() {
+        // raw profiles without units only have samples.
+        let unit = units.get(i).copied().unwrap_or_default();
+        // with multiple probes the outputs are suffixed with the index of the probe.
+        let output = |name: &str, ext: &str| {
+            if probes.len() == 1 {
+                format!("{}.{}", name, ext)
+            } else {
+                format!("{}-{}.{}", name, i, ext)
+            }
+        };
+        let threads = sample::threads(
+            samples.iter().filter(|sample| sample.probe == i as u32),
+            names,
+        );
+        match args.format {
+            Format::Flamegraph if args.threads == ThreadMode::Split => {
+                for thread in &threads {
+                    write_flamegraph(
+                        processes,
+                        std::slice::from_ref(thread),
+                        unit,
+                        false,
+                        format!("{} {} ({})", probe, thread.name, thread.tid),
+                        &output(&format!("collapsed-{}", thread.tid), "txt"),
+                        &output(&format!("flamegraph-{}", thread.tid), "svg"),
+                    )?;
+                }
+            }
+            Format::Flamegraph => write_flamegraph(
+                processes,
+                &threads,
+                unit,
+                args.threads == ThreadMode::Group,
+                probe.clone(),
+                &output("collapsed", "txt"),
+                &output("flamegraph", "svg"),
+            )?,
+            Format::Pprof if args.threads == ThreadMode::Split => {
+                for thread in &threads {
+                    let path = output(&format!("profile-{}", thread.tid), "pb");
+                    let threads = std::slice::from_ref(thread);
+                    pprof::write_pprof(processes, threads, unit, probe, &path)?;
+                }
+            }
+            Format::Pprof => {
+                pprof::write_pprof(processes, &threads, unit, probe, &output("profile", "pb"))?
+            }
+            Format::Speedscope => {
+                let path = output("profile", "speedscope.json");
+                speedscope::write_speedscope(processes, &threads, unit, probe, &path)?;
+            }
+            Format::Firefox => {
+                let path = output("profile", "firefox.json");
+                firefox::write_firefox(processes, &threads, unit, probe, &path)?;
+            }
+            Format::Annotate => {
+                let path = output("annotate", "json");
+                annotate::write_annotate(processes, &threads, unit, probe, &path)?;
+            }
+            Format::Raw => unreachable!(),
+        }
+    }
+    Ok(())
+}
+
+/// Replaces library names like `libc` in uprobe paths with the path of the library
+/// loaded by the program.
+fn resolve_library(info: &BinaryInfo, probe: &mut Probe) {
+    if let Probe::Uprobe {
+        path: Some(path), ..
+    }
+    | Probe::Uretprobe {
+        path: Some(path), ..
+    }
+    | Probe::Usdt {
+        path: Some(path), ..
+    } = probe
+    {
+        if path.exists() {
+            return;
+        }
+        if let Some(binary) = path.to_str().and_then(|name| info.library(name)) {
+            log::debug!(
+                "resolved {} to {}",
+                path.display(),
+                binary.elf.path().display()
+            );
+            *path = binary.elf.path().to_owned();
+        }
+    }
+}
+
+/// Writes the collapsed stacks and a flamegraph of the samples of `threads`.
+///
+/// When `group` is set, every thread gets a root frame named after the thread. When
+/// processes were spawned, the threads are put below a frame named after their process.
+/// Inlined functions are suffixed with `_[i]`.
 fn write_flamegraph(
-    info: &BinaryInfo,
-    iter: impl Iterator<Item = ([U64; 48], U32)>,
+    processes: &Processes,
+    threads: &[Thread],
+    unit: Unit,
+    group: bool,
     title: String,
+    collapsed: &str,
+    svg: &str,
 ) -> Result<()> {
     let mut f = OpenOptions::new()
         .create(true)
         .truncate(true)
         .write(true)
-        .open("collapsed.txt")?;
-
-    let mut symbols = Vec::with_capacity(48);
-    for (stack, count) in iter {
-        symbols.clear();
-        for ip in stack.iter() {
-            let ip = ip.get() as usize;
-            if ip == 0 {
-                break;
+        .open(collapsed)?;
+
+    let mut symbols = Vec::with_capacity(MAX_STACK_DEPTH + 2);
+    for thread in threads {
+        for sample in &thread.samples {
+            let info = processes.get(sample.pid, sample.exec);
+            symbols.clear();
+            symbols.extend(sample.kernel_frames());
+            for (i, ip) in sample.stack.iter().enumerate() {
+                let ip = ip.get() as usize;
+                if ip == 0 {
+                    break;
+                }
+                let frames = info.resolve_frames(ip, i == 0)?;
+                if frames.is_empty() {
+                    break;
+                }
+                for frame in frames {
+                    if frame.inlined {
+                        symbols.push(format!("{}_[i]", frame.name));
+                    } else {
+                        symbols.push(frame.name);
+                    }
+                }
             }
-            if let Some(symbol) = info.resolve_symbol(ip)? {
-                symbols.push(symbol);
-            } else {
-                break;
+            if sample.truncated {
+                symbols.push(TRUNCATED.to_string());
             }
+            if group {
+                symbols.push(format!("{}-{}", thread.name, thread.tid));
+            }
+            if processes.len() > 1 {
+                symbols.push(format!("{}-{}", thread.process, thread.pid));
+            }
+            symbols.reverse();
+            let mut collapsed = symbols.join(";");
+            collapsed.push(' ');
+            collapsed.push_str(&sample.count.to_string());
+            writeln!(f, "{}", collapsed)?;
         }
-        symbols.reverse();
-        let mut collapsed = symbols.join(";");
-        collapsed.push(' ');
-        collapsed.push_str(&count.to_string());
-        writeln!(f, "{}", collapsed)?;
     }
 
-    let collapsed = BufReader::new(File::open("collapsed.txt")?)
+    let collapsed = BufReader::new(File::open(collapsed)?)
         .lines()
         .collect::<Result<Vec<_>, _>>()?;
     let f = OpenOptions::new()
         .create(true)
         .truncate(true)
         .write(true)
-        .open("flamegraph.svg")?;
+        .open(svg)?;
     let mut options = Options::default();
     options.title = title;
+    options.count_name = unit.to_string();
+    let annotated = collapsed
+        .iter()
+        .any(|stack| stack.contains("_[k]") || stack.contains("_[i]"));
+    if annotated {
+        // the java palette colors kernel frames orange and inlined frames aqua.
+        options.colors = Palette::Multi(MultiPalette::Java);
+    }
     flamegraph::from_lines(&mut options, collapsed.iter().map(|s| s.as_str()), f)?;
     Ok(())
 }
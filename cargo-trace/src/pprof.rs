@@ -0,0 +1,467 @@
+//! Writer for the [pprof](https://github.com/google/pprof/blob/master/proto/profile.proto)
+//! profile format.
+use crate::process::Processes;
+use crate::sample::{Sample as StackSample, Thread, Unit, TRUNCATED};
+use anyhow::Result;
+use prost::Message;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::Write;
+
+#[derive(Clone, PartialEq, Message)]
+pub struct Profile {
+    #[prost(message, repeated, tag = "1")]
+    pub sample_type: Vec<ValueType>,
+    #[prost(message, repeated, tag = "2")]
+    pub sample: Vec<Sample>,
+    #[prost(message, repeated, tag = "3")]
+    pub mapping: Vec<Mapping>,
+    #[prost(message, repeated, tag = "4")]
+    pub location: Vec<Location>,
+    #[prost(message, repeated, tag = "5")]
+    pub function: Vec<Function>,
+    #[prost(string, repeated, tag = "6")]
+    pub string_table: Vec<String>,
+    #[prost(int64, tag = "7")]
+    pub drop_frames: i64,
+    #[prost(int64, tag = "8")]
+    pub keep_frames: i64,
+    #[prost(int64, tag = "9")]
+    pub time_nanos: i64,
+    #[prost(int64, tag = "10")]
+    pub duration_nanos: i64,
+    #[prost(message, optional, tag = "11")]
+    pub period_type: Option<ValueType>,
+    #[prost(int64, tag = "12")]
+    pub period: i64,
+    #[prost(int64, repeated, tag = "13")]
+    pub comment: Vec<i64>,
+    #[prost(int64, tag = "14")]
+    pub default_sample_type: i64,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct ValueType {
+    #[prost(int64, tag = "1")]
+    pub r#type: i64,
+    #[prost(int64, tag = "2")]
+    pub unit: i64,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct Sample {
+    /// Leaf first.
+    #[prost(uint64, repeated, tag = "1")]
+    pub location_id: Vec<u64>,
+    #[prost(int64, repeated, tag = "2")]
+    pub value: Vec<i64>,
+    #[prost(message, repeated, tag = "3")]
+    pub label: Vec<Label>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct Label {
+    #[prost(int64, tag = "1")]
+    pub key: i64,
+    #[prost(int64, tag = "2")]
+    pub str: i64,
+    #[prost(int64, tag = "3")]
+    pub num: i64,
+    #[prost(int64, tag = "4")]
+    pub num_unit: i64,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct Mapping {
+    #[prost(uint64, tag = "1")]
+    pub id: u64,
+    #[prost(uint64, tag = "2")]
+    pub memory_start: u64,
+    #[prost(uint64, tag = "3")]
+    pub memory_limit: u64,
+    #[prost(uint64, tag = "4")]
+    pub file_offset: u64,
+    #[prost(int64, tag = "5")]
+    pub filename: i64,
+    #[prost(int64, tag = "6")]
+    pub build_id: i64,
+    #[prost(bool, tag = "7")]
+    pub has_functions: bool,
+    #[prost(bool, tag = "8")]
+    pub has_filenames: bool,
+    #[prost(bool, tag = "9")]
+    pub has_line_numbers: bool,
+    #[prost(bool, tag = "10")]
+    pub has_inline_frames: bool,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct Location {
+    #[prost(uint64, tag = "1")]
+    pub id: u64,
+    #[prost(uint64, tag = "2")]
+    pub mapping_id: u64,
+    #[prost(uint64, tag = "3")]
+    pub address: u64,
+    /// Inlined functions first, the last line is the function they were inlined into.
+    #[prost(message, repeated, tag = "4")]
+    pub line: Vec<Line>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct Line {
+    #[prost(uint64, tag = "1")]
+    pub function_id: u64,
+    #[prost(int64, tag = "2")]
+    pub line: i64,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct Function {
+    #[prost(uint64, tag = "1")]
+    pub id: u64,
+    #[prost(int64, tag = "2")]
+    pub name: i64,
+    #[prost(int64, tag = "3")]
+    pub system_name: i64,
+    #[prost(int64, tag = "4")]
+    pub filename: i64,
+    #[prost(int64, tag = "5")]
+    pub start_line: i64,
+}
+
+/// Builds a profile, deduplicating strings, functions and locations.
+struct Builder<'a> {
+    processes: &'a Processes,
+    profile: Profile,
+    strings: HashMap<String, i64>,
+    functions: HashMap<(i64, i64), u64>,
+    locations: HashMap<(u32, u32, usize, bool), u64>,
+    kernel_locations: HashMap<String, u64>,
+    /// Number of mappings preceding the mappings of a process after an exec.
+    mappings: HashMap<(u32, u32), u64>,
+}
+
+impl<'a> Builder<'a> {
+    fn new(processes: &'a Processes, unit: Unit, title: &str) -> Self {
+        let mut builder = Self {
+            processes,
+            profile: Profile::default(),
+            strings: HashMap::new(),
+            functions: HashMap::new(),
+            locations: HashMap::new(),
+            kernel_locations: HashMap::new(),
+            mappings: HashMap::new(),
+        };
+        // the first entry of the string table must be the empty string.
+        builder.string("");
+        let (r#type, unit) = unit.value_type();
+        let sample_type = ValueType {
+            r#type: builder.string(r#type),
+            unit: builder.string(unit),
+        };
+        builder.profile.sample_type.push(sample_type);
+        let comment = builder.string(title);
+        builder.profile.comment.push(comment);
+        for (exec, info) in processes.iter() {
+            builder
+                .mappings
+                .insert((info.pid(), exec), builder.profile.mapping.len() as u64);
+            for binary in info.binaries() {
+                let filename = builder.string(&binary.elf.path().display().to_string());
+                let build_id = binary
+                    .elf
+                    .build_id()
+                    .map(|build_id| build_id.to_string())
+                    .unwrap_or_default();
+                let build_id = builder.string(&build_id);
+                let has_dwarf = binary.dwarf.is_some();
+                builder.profile.mapping.push(Mapping {
+                    id: builder.profile.mapping.len() as u64 + 1,
+                    memory_start: binary.start_addr as _,
+                    memory_limit: binary.end_addr as _,
+                    file_offset: 0,
+                    filename,
+                    build_id,
+                    has_functions: true,
+                    has_filenames: has_dwarf,
+                    has_line_numbers: has_dwarf,
+                    has_inline_frames: has_dwarf,
+                });
+            }
+        }
+        builder
+    }
+
+    fn string(&mut self, s: &str) -> i64 {
+        if let Some(id) = self.strings.get(s) {
+            return *id;
+        }
+        let id = self.profile.string_table.len() as i64;
+        self.profile.string_table.push(s.to_string());
+        self.strings.insert(s.to_string(), id);
+        id
+    }
+
+    fn function(&mut self, name: &str, system_name: &str, filename: &str) -> u64 {
+        let name = self.string(name);
+        let system_name = self.string(system_name);
+        let filename = self.string(filename);
+        if let Some(id) = self.functions.get(&(system_name, filename)) {
+            return *id;
+        }
+        let id = self.profile.function.len() as u64 + 1;
+        self.profile.function.push(Function {
+            id,
+            name,
+            system_name,
+            filename,
+            start_line: 0,
+        });
+        self.functions.insert((system_name, filename), id);
+        id
+    }
+
+    /// Returns the location of `ip` in the process `pid` after it's `exec`th exec,
+    /// addresses outside of the loaded binaries are kept without a mapping.
+    fn location(&mut self, pid: u32, exec: u32, ip: usize, leaf: bool) -> Result<u64> {
+        if let Some(id) = self.locations.get(&(pid, exec, ip, leaf)) {
+            return Ok(*id);
+        }
+        let info = self.processes.get(pid, exec);
+        let mut location = Location {
+            id: self.profile.location.len() as u64 + 1,
+            mapping_id: 0,
+            address: ip as _,
+            line: vec![],
+        };
+        // return addresses are looked up inside of the call, like in `resolve_frames`.
+        let lookup = if leaf { ip } else { ip - 1 };
+        if let Some((i, binary)) = info.binary_index(lookup).zip(info.binary(lookup)) {
+            let offset = lookup - binary.start_addr;
+            location.mapping_id = self.mappings[&(pid, exec)] + i as u64 + 1;
+            if let Some(dwarf) = binary.dwarf.as_ref() {
+                let mut frames = dwarf.find_frames(offset)?;
+                while let Some(frame) = frames.next()? {
+                    let function = if let Some(function) = frame.function {
+                        function
+                    } else {
+                        continue;
+                    };
+                    let (file, line) = frame
+                        .location
+                        .map(|loc| (loc.file.unwrap_or_default(), loc.line.unwrap_or_default()))
+                        .unwrap_or_default();
+                    let function_id =
+                        self.function(&function.demangle()?, &function.raw_name()?, file);
+                    location.line.push(Line {
+                        function_id,
+                        line: line as _,
+                    });
+                }
+            }
+            if location.line.is_empty() {
+                if let Some(symbol) = binary.elf.resolve_address(offset)? {
+                    let function_id = self.function(symbol, symbol, "");
+                    location.line.push(Line {
+                        function_id,
+                        line: 0,
+                    });
+                }
+            }
+        }
+        let id = location.id;
+        self.profile.location.push(location);
+        self.locations.insert((pid, exec, ip, leaf), id);
+        Ok(id)
+    }
+
+    /// Returns the location of a kernel frame, kernel frames have neither an address
+    /// nor a mapping.
+    fn kernel_location(&mut self, name: String) -> u64 {
+        if let Some(id) = self.kernel_locations.get(&name) {
+            return *id;
+        }
+        let function_id = self.function(&name, &name, "");
+        let id = self.profile.location.len() as u64 + 1;
+        self.profile.location.push(Location {
+            id,
+            mapping_id: 0,
+            address: 0,
+            line: vec![Line {
+                function_id,
+                line: 0,
+            }],
+        });
+        self.kernel_locations.insert(name, id);
+        id
+    }
+
+    fn sample(&mut self, sample: &StackSample, thread: &Thread) -> Result<()> {
+        let mut location_id = Vec::with_capacity(sample.kernel_stack.len() + sample.stack.len());
+        for name in sample.kernel_frames() {
+            location_id.push(self.kernel_location(name));
+        }
+        for (i, ip) in sample.stack.iter().enumerate() {
+            let ip = ip.get() as usize;
+            if ip == 0 {
+                break;
+            }
+            location_id.push(self.location(thread.pid, sample.exec, ip, i == 0)?);
+        }
+        if sample.truncated {
+            location_id.push(self.kernel_location(TRUNCATED.to_string()));
+        }
+        // labels allow filtering and grouping by thread, e.g. with `pprof -tagroot thread`.
+        let label = vec![
+            Label {
+                key: self.string("process"),
+                str: self.string(&thread.process),
+                num: 0,
+                num_unit: 0,
+            },
+            Label {
+                key: self.string("pid"),
+                str: 0,
+                num: thread.pid as _,
+                num_unit: 0,
+            },
+            Label {
+                key: self.string("thread"),
+                str: self.string(&thread.name),
+                num: 0,
+                num_unit: 0,
+            },
+            Label {
+                key: self.string("thread_id"),
+                str: 0,
+                num: thread.tid as _,
+                num_unit: 0,
+            },
+        ];
+        self.profile.sample.push(Sample {
+            location_id,
+            value: vec![sample.count as _],
+            label,
+        });
+        Ok(())
+    }
+}
+
+/// Writes the samples as an uncompressed pprof profile, samples are labeled with
+/// their process and thread.
+pub fn write_pprof(
+    processes: &Processes,
+    threads: &[Thread],
+    unit: Unit,
+    title: &str,
+    path: &str,
+) -> Result<()> {
+    let mut builder = Builder::new(processes, unit, title);
+    for thread in threads {
+        for sample in &thread.samples {
+            builder.sample(sample, thread)?;
+        }
+    }
+    let mut buf = Vec::with_capacity(builder.profile.encoded_len());
+    builder.profile.encode(&mut buf)?;
+    File::create(path)?.write_all(&buf)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bpf::utils::BinaryInfo;
+
+    #[test]
+    fn build_profile() -> Result<()> {
+        let processes = Processes::new(BinaryInfo::from_binaries(1, vec![]));
+        let thread = Thread {
+            pid: 1,
+            tid: 2,
+            name: "worker".into(),
+            process: "main".into(),
+            samples: vec![],
+        };
+        let sample = StackSample {
+            kernel_stack: vec!["schedule".into()],
+            ..StackSample::new(0, 1, 2, 0x10, 3)
+        };
+        let truncated = StackSample {
+            truncated: true,
+            count: 2,
+            ..sample.clone()
+        };
+        let mut builder = Builder::new(&processes, Unit::Nanoseconds, "offcpu");
+        builder.sample(&sample, &thread)?;
+        builder.sample(&truncated, &thread)?;
+        let mut buf = vec![];
+        builder.profile.encode(&mut buf)?;
+        let profile = Profile::decode(&buf[..])?;
+        let string = |id: i64| profile.string_table[id as usize].as_str();
+
+        assert_eq!(string(0), "");
+        let sample_type = &profile.sample_type[0];
+        assert_eq!(
+            (string(sample_type.r#type), string(sample_type.unit)),
+            ("offcpu", "nanoseconds")
+        );
+        let samples: Vec<_> = profile
+            .sample
+            .iter()
+            .map(|sample| (sample.location_id.as_slice(), sample.value.as_slice()))
+            .collect();
+        assert_eq!(
+            samples,
+            [(&[1, 2][..], &[3][..]), (&[1, 2, 3][..], &[2][..])]
+        );
+        let labels: Vec<_> = profile.sample[0]
+            .label
+            .iter()
+            .map(|label| (string(label.key), string(label.str), label.num))
+            .collect();
+        assert_eq!(
+            labels,
+            [
+                ("process", "main", 0),
+                ("pid", "", 1),
+                ("thread", "worker", 0),
+                ("thread_id", "", 2)
+            ]
+        );
+
+        // kernel frames have neither an address nor a mapping, user frames outside of
+        // the binaries have no function.
+        let locations: Vec<_> = profile
+            .location
+            .iter()
+            .map(|location| {
+                let functions: Vec<_> = location.line.iter().map(|line| line.function_id).collect();
+                (
+                    location.id,
+                    location.mapping_id,
+                    location.address,
+                    functions,
+                )
+            })
+            .collect();
+        assert_eq!(
+            locations,
+            [(1, 0, 0, vec![1]), (2, 0, 0x10, vec![]), (3, 0, 0, vec![2])]
+        );
+        let functions: Vec<_> = profile
+            .function
+            .iter()
+            .map(|function| {
+                (
+                    function.id,
+                    string(function.name),
+                    string(function.filename),
+                )
+            })
+            .collect();
+        assert_eq!(functions, [(1, "schedule_[k]", ""), (2, TRUNCATED, "")]);
+        Ok(())
+    }
+}
@@ -0,0 +1,87 @@
+use bpf::utils::BinaryInfo;
+use std::collections::BTreeMap;
+
+/// Binaries of the traced process and of the processes it spawned by pid and by the
+/// number of times the process called exec before they were loaded.
+pub struct Processes {
+    root: u32,
+    infos: BTreeMap<(u32, u32), BinaryInfo>,
+    /// Used for processes that exited before their binaries were loaded.
+    unknown: BinaryInfo,
+}
+
+impl Processes {
+    pub fn new(root: BinaryInfo) -> Self {
+        let pid = root.pid();
+        let mut infos = BTreeMap::new();
+        infos.insert((pid, 0), root);
+        Self {
+            root: pid,
+            infos,
+            unknown: BinaryInfo::from_binaries(0, vec![]),
+        }
+    }
+
+    pub fn root(&self) -> &BinaryInfo {
+        &self.infos[&(self.root, 0)]
+    }
+
+    pub fn root_mut(&mut self) -> &mut BinaryInfo {
+        self.infos.get_mut(&(self.root, 0)).unwrap()
+    }
+
+    pub fn contains(&self, pid: u32, exec: u32) -> bool {
+        self.infos.contains_key(&(pid, exec))
+    }
+
+    /// Adds the binaries a process had after it's `exec`th exec, the binaries it had
+    /// before are kept to symbolize the samples taken before.
+    pub fn insert(&mut self, exec: u32, info: BinaryInfo) {
+        self.infos.insert((info.pid(), exec), info);
+    }
+
+    /// Returns the binaries of the process `pid` after it's `exec`th exec, the frames
+    /// of unknown processes can't be symbolized.
+    pub fn get(&self, pid: u32, exec: u32) -> &BinaryInfo {
+        self.infos.get(&(pid, exec)).unwrap_or(&self.unknown)
+    }
+
+    pub fn get_mut(&mut self, pid: u32, exec: u32) -> Option<&mut BinaryInfo> {
+        self.infos.get_mut(&(pid, exec))
+    }
+
+    /// Number of processes.
+    pub fn len(&self) -> usize {
+        let mut pids: Vec<_> = self.infos.keys().map(|(pid, _)| pid).collect();
+        pids.dedup();
+        pids.len()
+    }
+
+    /// Returns the binaries of all processes with the number of execs before they
+    /// were loaded.
+    pub fn iter(&self) -> impl Iterator<Item = (u32, &BinaryInfo)> + '_ {
+        self.infos.iter().map(|((_, exec), info)| (*exec, info))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keep_binaries_of_each_exec() {
+        let mut processes = Processes::new(BinaryInfo::from_binaries(1, vec![]));
+        processes.insert(0, BinaryInfo::from_binaries(2, vec![]));
+        processes.insert(1, BinaryInfo::from_binaries(2, vec![]));
+        assert!(processes.contains(2, 0));
+        assert!(processes.contains(2, 1));
+        assert!(!processes.contains(1, 1));
+        assert_eq!(processes.len(), 2);
+        let execs: Vec<_> = processes
+            .iter()
+            .map(|(exec, info)| (info.pid(), exec))
+            .collect();
+        assert_eq!(execs, [(1, 0), (2, 0), (2, 1)]);
+        assert_eq!(processes.get(3, 0).pid(), 0);
+    }
+}
@@ -0,0 +1,356 @@
+//! Unsymbolized profiles that can be symbolized later, possibly on another machine.
+use crate::process::Processes;
+use crate::sample::{Sample, Unit, MAX_STACK_DEPTH};
+use anyhow::{bail, Result};
+use bpf::utils::{Binary, BinaryInfo, Elf};
+use bpf::U64;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{BufReader, BufWriter};
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Deserialize, PartialEq, Serialize)]
+pub struct RawProfile {
+    pub pid: u32,
+    pub probes: Vec<String>,
+    /// Unit of the samples of each probe.
+    pub units: Vec<Unit>,
+    /// Thread names by thread id.
+    pub threads: BTreeMap<u32, String>,
+    pub binaries: Vec<RawBinary>,
+    /// Binaries of the processes spawned by the program by pid.
+    pub children: BTreeMap<u32, Vec<RawBinary>>,
+    /// Binaries of the processes that called exec.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub execs: Vec<RawExec>,
+    pub samples: Vec<RawSample>,
+}
+
+#[derive(Debug, Deserialize, PartialEq, Serialize)]
+pub struct RawExec {
+    pub pid: u32,
+    /// Number of execs of the process before the binaries were loaded.
+    pub exec: u32,
+    pub binaries: Vec<RawBinary>,
+}
+
+#[derive(Debug, Deserialize, PartialEq, Serialize)]
+pub struct RawBinary {
+    pub start_addr: usize,
+    pub end_addr: usize,
+    pub path: PathBuf,
+    pub build_id: Option<String>,
+    /// Set when the binary was unloaded with `dlclose` while the program was traced.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub unloaded: bool,
+}
+
+#[derive(Debug, Deserialize, PartialEq, Serialize)]
+pub struct RawSample {
+    pub probe: u32,
+    pub pid: u32,
+    pub tid: u32,
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub exec: u32,
+    /// Kernel frames are symbolized when recording, kernel addresses are meaningless
+    /// on other machines or after a reboot.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub kernel_stack: Vec<String>,
+    /// Stack leaf first without the trailing zeros.
+    pub stack: Vec<u64>,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub truncated: bool,
+    pub count: u64,
+    /// Time and weight of each streamed sample.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub events: Vec<(u64, u64)>,
+}
+
+impl RawProfile {
+    pub fn new(
+        processes: &Processes,
+        probes: &[String],
+        units: &[Unit],
+        threads: &BTreeMap<u32, String>,
+        samples: &[Sample],
+    ) -> Self {
+        let pid = processes.root().pid();
+        let mut children = BTreeMap::new();
+        let mut execs = vec![];
+        for (exec, info) in processes.iter() {
+            let binaries = RawBinary::from_info(info);
+            if exec > 0 {
+                execs.push(RawExec {
+                    pid: info.pid(),
+                    exec,
+                    binaries,
+                });
+            } else if info.pid() != pid {
+                children.insert(info.pid(), binaries);
+            }
+        }
+        let samples = samples
+            .iter()
+            .map(|sample| RawSample {
+                probe: sample.probe,
+                pid: sample.pid,
+                tid: sample.tid,
+                exec: sample.exec,
+                kernel_stack: sample.kernel_stack.clone(),
+                stack: sample
+                    .stack
+                    .iter()
+                    .map(|ip| ip.get())
+                    .take_while(|ip| *ip != 0)
+                    .collect(),
+                truncated: sample.truncated,
+                count: sample.count,
+                events: sample.events.clone(),
+            })
+            .collect();
+        Self {
+            pid,
+            probes: probes.to_vec(),
+            units: units.to_vec(),
+            threads: threads.clone(),
+            binaries: RawBinary::from_info(processes.root()),
+            children,
+            execs,
+            samples,
+        }
+    }
+
+    pub fn read(path: &Path) -> Result<Self> {
+        Ok(serde_json::from_reader(BufReader::new(File::open(path)?))?)
+    }
+
+    pub fn write(&self, path: &Path) -> Result<()> {
+        serde_json::to_writer(BufWriter::new(File::create(path)?), self)?;
+        Ok(())
+    }
+
+    pub fn samples(&self) -> Vec<Sample> {
+        self.samples
+            .iter()
+            .map(|sample| {
+                let mut stack = [U64::new(0); MAX_STACK_DEPTH];
+                for (slot, ip) in stack.iter_mut().zip(sample.stack.iter()) {
+                    *slot = U64::new(*ip);
+                }
+                Sample {
+                    probe: sample.probe,
+                    pid: sample.pid,
+                    tid: sample.tid,
+                    exec: sample.exec,
+                    kernel_stack: sample.kernel_stack.clone(),
+                    stack,
+                    truncated: sample.truncated,
+                    count: sample.count,
+                    events: sample.events.clone(),
+                }
+            })
+            .collect()
+    }
+
+    /// Loads the binaries of the profiled processes, looking them up by build id in
+    /// `debug_dir` before falling back to the recorded paths.
+    ///
+    /// Binaries that can't be found are skipped, their frames remain unsymbolized.
+    pub fn processes(&self, debug_dir: Option<&Path>) -> Result<Processes> {
+        let root = binary_info(self.pid, &self.binaries, debug_dir);
+        if root.is_empty() {
+            bail!("couldn't find any of the profiled binaries");
+        }
+        let mut processes = Processes::new(root);
+        for (pid, binaries) in &self.children {
+            processes.insert(0, binary_info(*pid, binaries, debug_dir));
+        }
+        for exec in &self.execs {
+            let info = binary_info(exec.pid, &exec.binaries, debug_dir);
+            processes.insert(exec.exec, info);
+        }
+        Ok(processes)
+    }
+}
+
+fn is_zero(n: &u32) -> bool {
+    *n == 0
+}
+
+fn binary_info(pid: u32, binaries: &[RawBinary], debug_dir: Option<&Path>) -> BinaryInfo {
+    let mut map = vec![];
+    let mut unloaded = vec![];
+    for binary in binaries {
+        if let Some(elf) = binary.locate(debug_dir) {
+            let dwarf = elf.dwarf().ok();
+            let loaded = Binary {
+                start_addr: binary.start_addr,
+                end_addr: binary.end_addr,
+                elf,
+                dwarf,
+            };
+            if binary.unloaded {
+                unloaded.push(loaded);
+            } else {
+                map.push(loaded);
+            }
+        } else {
+            log::warn!("couldn't find {}", binary.path.display());
+        }
+    }
+    BinaryInfo::from_binaries(pid, map).with_unloaded(unloaded)
+}
+
+impl RawBinary {
+    fn from_info(info: &BinaryInfo) -> Vec<Self> {
+        info.binaries()
+            .enumerate()
+            .map(|(i, binary)| Self {
+                start_addr: binary.start_addr,
+                end_addr: binary.end_addr,
+                path: binary.elf.path().to_owned(),
+                build_id: binary.elf.build_id().ok().map(|id| id.to_string()),
+                unloaded: i >= info.len(),
+            })
+            .collect()
+    }
+
+    fn candidates(&self, debug_dir: Option<&Path>) -> Vec<PathBuf> {
+        let mut candidates = vec![];
+        if let Some(debug_dir) = debug_dir {
+            if let Some(build_id) = self.build_id.as_ref().filter(|id| id.len() > 2) {
+                candidates.push(
+                    debug_dir
+                        .join(".build-id")
+                        .join(&build_id[..2])
+                        .join(format!("{}.debug", &build_id[2..])),
+                );
+            }
+            if let Some(file_name) = self.path.file_name() {
+                candidates.push(debug_dir.join(file_name));
+            }
+        }
+        candidates.push(self.path.clone());
+        candidates
+    }
+
+    /// Returns the first candidate with a matching build id.
+    fn locate(&self, debug_dir: Option<&Path>) -> Option<Elf> {
+        for path in self.candidates(debug_dir) {
+            let elf = match Elf::open(&path) {
+                Ok(elf) => elf,
+                Err(_) => continue,
+            };
+            let build_id = elf.build_id().ok().map(|id| id.to_string());
+            if self.build_id.is_none() || build_id == self.build_id {
+                log::debug!("using {} for {}", path.display(), self.path.display());
+                return Some(elf);
+            }
+            log::debug!("build id of {} doesn't match", path.display());
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn build_id_candidates() {
+        let binary = RawBinary {
+            start_addr: 0,
+            end_addr: 0,
+            path: "/usr/lib/libc.so.6".into(),
+            build_id: Some("abcdef".into()),
+            unloaded: false,
+        };
+        assert_eq!(
+            binary.candidates(Some(Path::new("/debug"))),
+            [
+                PathBuf::from("/debug/.build-id/ab/cdef.debug"),
+                PathBuf::from("/debug/libc.so.6"),
+                PathBuf::from("/usr/lib/libc.so.6"),
+            ]
+        );
+        assert_eq!(
+            binary.candidates(None),
+            [PathBuf::from("/usr/lib/libc.so.6")]
+        );
+    }
+
+    #[test]
+    fn truncated_stacks() {
+        let json = r#"{"probe":0,"pid":1,"tid":1,"stack":[1,2],"count":3}"#;
+        let sample: RawSample = serde_json::from_str(json).unwrap();
+        assert!(!sample.truncated);
+        assert!(!serde_json::to_string(&sample)
+            .unwrap()
+            .contains("truncated"));
+        let sample = RawSample {
+            truncated: true,
+            ..sample
+        };
+        let json = serde_json::to_string(&sample).unwrap();
+        let sample: RawSample = serde_json::from_str(&json).unwrap();
+        assert!(sample.truncated);
+    }
+    #[test]
+    fn write_and_read() -> Result<()> {
+        let binary = |path: &str, unloaded| RawBinary {
+            start_addr: 0x1000,
+            end_addr: 0x2000,
+            path: path.into(),
+            build_id: Some("abcdef".into()),
+            unloaded,
+        };
+        let mut threads = BTreeMap::new();
+        threads.insert(1, "main".to_string());
+        let mut children = BTreeMap::new();
+        children.insert(2, vec![binary("/bin/sh", false)]);
+        let profile = RawProfile {
+            pid: 1,
+            probes: vec!["profile:hz:99".into(), "kprobe:finish_task_switch".into()],
+            units: vec![Unit::Samples, Unit::Nanoseconds],
+            threads,
+            binaries: vec![binary("/bin/a", false), binary("/lib/b.so", true)],
+            children,
+            execs: vec![RawExec {
+                pid: 2,
+                exec: 1,
+                binaries: vec![binary("/bin/c", false)],
+            }],
+            samples: vec![
+                RawSample {
+                    probe: 0,
+                    pid: 1,
+                    tid: 1,
+                    exec: 0,
+                    kernel_stack: vec![],
+                    stack: vec![0x1010, 0x1020],
+                    truncated: false,
+                    count: 3,
+                    events: vec![],
+                },
+                RawSample {
+                    probe: 1,
+                    pid: 2,
+                    tid: 3,
+                    exec: 1,
+                    kernel_stack: vec!["schedule".into()],
+                    stack: vec![0x1030],
+                    truncated: true,
+                    count: 2,
+                    events: vec![(10, 1), (20, 1)],
+                },
+            ],
+        };
+        let path = std::env::temp_dir().join(format!("cargo-trace-{}.raw", std::process::id()));
+        profile.write(&path)?;
+        let read = RawProfile::read(&path);
+        std::fs::remove_file(&path)?;
+        assert_eq!(read?, profile);
+        Ok(())
+    }
+}
@@ -0,0 +1,180 @@
+use bpf::U64;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// What the counts of the samples measure.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Unit {
+    /// Number of times a probe fired.
+    #[default]
+    Samples,
+    /// Nanoseconds a thread was off cpu.
+    Nanoseconds,
+    /// Allocated bytes.
+    Bytes,
+    /// Sum of the values probes were weighted by.
+    Weight,
+}
+
+impl Unit {
+    /// Name of the measured value and of it's unit like in pprof profiles.
+    pub fn value_type(&self) -> (&'static str, &'static str) {
+        match self {
+            Self::Samples => ("samples", "count"),
+            Self::Nanoseconds => ("offcpu", "nanoseconds"),
+            Self::Bytes => ("space", "bytes"),
+            Self::Weight => ("weight", "count"),
+        }
+    }
+}
+
+impl std::fmt::Display for Unit {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::Samples => write!(f, "samples"),
+            Self::Nanoseconds => write!(f, "ns"),
+            Self::Bytes => write!(f, "bytes"),
+            Self::Weight => write!(f, "weight"),
+        }
+    }
+}
+
+/// Maximum number of user frames unwound by the bpf program.
+pub const MAX_STACK_DEPTH: usize = 128;
+
+/// Root frame of stacks that were deeper than `MAX_STACK_DEPTH`.
+pub const TRUNCATED: &str = "[truncated]";
+
+/// Time in nanoseconds since boot and weight of streamed samples.
+pub type Events = Vec<(u64, u64)>;
+
+/// Stack sampled by a probe, leaf first.
+#[derive(Clone)]
+pub struct Sample {
+    pub probe: u32,
+    pub pid: u32,
+    pub tid: u32,
+    /// Number of times the process called exec before the sample was taken.
+    pub exec: u32,
+    /// Symbolized kernel frames leaf first, they precede the user stack.
+    pub kernel_stack: Vec<String>,
+    pub stack: [U64; MAX_STACK_DEPTH],
+    /// The unwinder gave up before reaching the root of the stack.
+    pub truncated: bool,
+    pub count: u64,
+    /// Time in nanoseconds since boot and weight of each streamed sample, empty when the
+    /// samples were counted by the bpf program.
+    pub events: Events,
+}
+
+impl Sample {
+    /// Kernel frames suffixed with `_[k]`, which flamegraphs color differently.
+    pub fn kernel_frames(&self) -> impl Iterator<Item = String> + '_ {
+        self.kernel_stack
+            .iter()
+            .map(|symbol| format!("{}_[k]", symbol))
+    }
+}
+
+#[cfg(test)]
+impl Sample {
+    /// Sample of thread `tid` of process `pid` with a single frame `ip`, or no frames if
+    /// `ip` is zero.
+    pub fn new(probe: u32, pid: u32, tid: u32, ip: u64, count: u64) -> Self {
+        let mut stack = [U64::new(0); MAX_STACK_DEPTH];
+        stack[0] = U64::new(ip);
+        Self {
+            probe,
+            pid,
+            tid,
+            exec: 0,
+            kernel_stack: vec![],
+            stack,
+            truncated: false,
+            count,
+            events: vec![],
+        }
+    }
+}
+
+/// Samples of a single thread.
+pub struct Thread {
+    pub pid: u32,
+    pub tid: u32,
+    pub name: String,
+    /// Name of the process, which is the name of it's main thread.
+    pub process: String,
+    pub samples: Vec<Sample>,
+}
+
+/// Groups the samples by thread, sorted by process and thread id.
+///
+/// Threads without a name in `names` are named after their id.
+pub fn threads<'a>(
+    samples: impl Iterator<Item = &'a Sample>,
+    names: &BTreeMap<u32, String>,
+) -> Vec<Thread> {
+    let mut threads = BTreeMap::new();
+    for sample in samples {
+        threads
+            .entry((sample.pid, sample.tid))
+            .or_insert_with(|| Thread {
+                pid: sample.pid,
+                tid: sample.tid,
+                name: name(names, sample.tid),
+                process: name(names, sample.pid),
+                samples: vec![],
+            })
+            .samples
+            .push(sample.clone());
+    }
+    threads.into_values().collect()
+}
+
+fn name(names: &BTreeMap<u32, String>, id: u32) -> String {
+    names.get(&id).cloned().unwrap_or_else(|| id.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn group_by_thread() {
+        let sample = |pid, tid| Sample::new(0, pid, tid, 0, 1);
+        let samples = [sample(3, 3), sample(1, 2), sample(1, 1), sample(1, 2)];
+        let mut names = BTreeMap::new();
+        names.insert(1, "main".to_string());
+        let threads = threads(samples.iter(), &names);
+        let threads: Vec<_> = threads
+            .iter()
+            .map(|thread| {
+                (
+                    thread.process.as_str(),
+                    thread.tid,
+                    thread.name.as_str(),
+                    thread.samples.len(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            threads,
+            [
+                ("main", 1, "main", 1),
+                ("main", 2, "2", 2),
+                ("3", 3, "3", 1)
+            ]
+        );
+    }
+
+    #[test]
+    fn suffix_kernel_frames() {
+        let sample = Sample {
+            kernel_stack: vec!["do_syscall_64".into(), "entry_SYSCALL_64".into()],
+            ..Sample::new(0, 1, 1, 0, 1)
+        };
+        let frames: Vec<_> = sample.kernel_frames().collect();
+        assert_eq!(frames, ["do_syscall_64_[k]", "entry_SYSCALL_64_[k]"]);
+    }
+}
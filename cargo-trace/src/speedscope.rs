@@ -0,0 +1,150 @@
+//! Writer for the [speedscope](https://www.speedscope.app/file-format-schema.json)
+//! file format.
+use crate::frames::{Frame, FrameTable};
+use crate::process::Processes;
+use crate::sample::{Thread, Unit};
+use anyhow::Result;
+use serde::Serialize;
+use std::fs::File;
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct SpeedscopeFile<'a> {
+    #[serde(rename = "$schema")]
+    schema: &'static str,
+    shared: Shared<'a>,
+    profiles: Vec<Profile>,
+    name: &'a str,
+    active_profile_index: usize,
+    exporter: &'static str,
+}
+
+#[derive(Serialize)]
+struct Shared<'a> {
+    frames: &'a [Frame],
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Profile {
+    r#type: &'static str,
+    name: String,
+    unit: &'static str,
+    start_value: u64,
+    end_value: u64,
+    /// Frame indices root first.
+    samples: Vec<Vec<usize>>,
+    weights: Vec<u64>,
+}
+
+/// Writes a sampled speedscope profile with one profile per thread.
+pub fn write_speedscope(
+    processes: &Processes,
+    threads: &[Thread],
+    unit: Unit,
+    title: &str,
+    path: &str,
+) -> Result<()> {
+    let mut frames = FrameTable::new(processes);
+    let mut profiles = Vec::with_capacity(threads.len());
+    for thread in threads {
+        let mut name = format!("{} ({})", thread.name, thread.tid);
+        if processes.len() > 1 {
+            name = format!("{} ({}) {}", thread.process, thread.pid, name);
+        }
+        let mut profile = Profile {
+            r#type: "sampled",
+            name,
+            unit: match unit {
+                Unit::Samples | Unit::Weight => "none",
+                Unit::Nanoseconds => "nanoseconds",
+                Unit::Bytes => "bytes",
+            },
+            start_value: 0,
+            end_value: 0,
+            samples: Vec::with_capacity(thread.samples.len()),
+            weights: Vec::with_capacity(thread.samples.len()),
+        };
+        let mut samples = Vec::with_capacity(thread.samples.len());
+        for sample in &thread.samples {
+            let stack = frames.stack(sample)?;
+            if sample.events.is_empty() {
+                samples.push((0, stack, sample.count));
+                continue;
+            }
+            for (time, weight) in &sample.events {
+                samples.push((*time, stack.clone(), *weight));
+            }
+        }
+        // streamed samples are shown in the order they were taken.
+        samples.sort_by_key(|(time, _, _)| *time);
+        for (_, stack, weight) in samples {
+            profile.samples.push(stack);
+            profile.weights.push(weight);
+            profile.end_value += weight;
+        }
+        profiles.push(profile);
+    }
+    let file = SpeedscopeFile {
+        schema: "https://www.speedscope.app/file-format-schema.json",
+        shared: Shared {
+            frames: frames.frames(),
+        },
+        profiles,
+        name: title,
+        active_profile_index: 0,
+        exporter: concat!("cargo-trace@", env!("CARGO_PKG_VERSION")),
+    };
+    serde_json::to_writer(File::create(path)?, &file)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sample::Sample;
+    use bpf::utils::BinaryInfo;
+    use serde_json::{json, Value};
+
+    #[test]
+    fn frames_and_weights() -> Result<()> {
+        let processes = Processes::new(BinaryInfo::from_binaries(1, vec![]));
+        let sample = |symbol: &str, count| Sample {
+            kernel_stack: vec![symbol.into(), "entry_SYSCALL_64".into()],
+            ..Sample::new(0, 1, 1, 0, count)
+        };
+        let streamed = Sample {
+            events: vec![(20, 4), (10, 1)],
+            ..sample("schedule", 5)
+        };
+        let thread = Thread {
+            pid: 1,
+            tid: 1,
+            name: "main".into(),
+            process: "main".into(),
+            samples: vec![sample("do_sys_poll", 3), streamed],
+        };
+        let path = std::env::temp_dir().join(format!("cargo-trace-{}.json", std::process::id()));
+        let path = path.to_str().unwrap();
+        write_speedscope(&processes, &[thread], Unit::Bytes, "heap", path)?;
+        let file: Result<Value, _> = serde_json::from_reader(File::open(path)?);
+        std::fs::remove_file(path)?;
+        let file = file?;
+        assert_eq!(
+            file["shared"]["frames"],
+            json!([
+                { "name": "do_sys_poll_[k]" },
+                { "name": "entry_SYSCALL_64_[k]" },
+                { "name": "schedule_[k]" },
+            ])
+        );
+        let profile = &file["profiles"][0];
+        assert_eq!(profile["name"], "main (1)");
+        assert_eq!(profile["unit"], "bytes");
+        // samples are root first, streamed samples are ordered by time.
+        assert_eq!(profile["samples"], json!([[1, 0], [1, 2], [1, 2]]));
+        assert_eq!(profile["weights"], json!([3, 1, 4]));
+        assert_eq!(profile["endValue"], 8);
+        Ok(())
+    }
+}
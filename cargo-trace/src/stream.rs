@@ -0,0 +1,148 @@
+//! Samples streamed to user space one by one instead of being counted by the bpf program.
+//!
+//! Every sample is written to a perf buffer with the time and cpu it was taken on. The
+//! buffers are drained while the program is running and the samples are counted by stack,
+//! the same way `USER_STACK` counts them. The time of each sample is kept for the outputs
+//! with a timeline.
+use crate::sample::Events;
+use crate::StackKey;
+use anyhow::Result;
+use bpf::{Bpf, BpfPerfBuffer, U32, U64};
+use std::collections::{BTreeSet, HashMap};
+use std::time::Duration;
+use zerocopy::{AsBytes, FromBytes, Unaligned};
+
+/// Size of the buffer of each cpu in pages.
+const PAGES: usize = 64;
+
+#[derive(Clone, Copy, AsBytes, FromBytes, Unaligned)]
+#[repr(C)]
+pub struct Event {
+    /// Nanoseconds since boot.
+    time: U64,
+    weight: U64,
+    cpu: U32,
+    _pad: U32,
+    key: StackKey,
+}
+
+pub struct Stream {
+    buffer: BpfPerfBuffer<Event>,
+    counts: Counts,
+}
+
+/// Streamed samples counted by stack.
+#[derive(Default)]
+struct Counts {
+    /// Time and weight of the samples of each stack.
+    stacks: HashMap<StackKey, Events>,
+    samples: u64,
+    cpus: BTreeSet<u32>,
+    /// Time of the first and the last sample.
+    span: Option<(u64, u64)>,
+}
+
+impl Counts {
+    fn add(&mut self, events: &[Event]) {
+        for event in events {
+            let time = event.time.get();
+            let stack = self.stacks.entry(event.key).or_default();
+            stack.push((time, event.weight.get()));
+            self.samples += 1;
+            self.cpus.insert(event.cpu.get());
+            self.span = Some(match self.span {
+                Some((start, end)) => (start.min(time), end.max(time)),
+                None => (time, time),
+            });
+        }
+    }
+}
+
+impl Stream {
+    /// Opens the perf buffer and switches the bpf program to streaming.
+    pub fn new(bpf: &mut Bpf) -> Result<Self> {
+        let buffer = bpf.perf_buffer("SAMPLES", PAGES)?;
+        bpf.array::<U32>("STREAM")?
+            .insert(&U32::new(0), &U32::new(1))?;
+        Ok(Self {
+            buffer,
+            counts: Counts::default(),
+        })
+    }
+
+    /// Waits up to `timeout` for samples and counts the samples that arrived.
+    pub fn poll(&mut self, timeout: Duration) -> Result<()> {
+        let events = self.buffer.poll(timeout)?;
+        self.counts.add(&events);
+        Ok(())
+    }
+
+    /// Count of each stack, like the entries of `USER_STACK`, followed by the time and
+    /// weight of it's samples.
+    pub fn stacks(&self) -> Vec<(StackKey, U64, Events)> {
+        self.counts
+            .stacks
+            .iter()
+            .map(|(key, events)| {
+                let count = events.iter().map(|(_, weight)| weight).sum();
+                (*key, U64::new(count), events.clone())
+            })
+            .collect()
+    }
+
+    /// Number of samples that were lost because a buffer was full.
+    pub fn lost(&self) -> u64 {
+        self.buffer.lost()
+    }
+
+    /// Logs how many samples were streamed on how many cpus over which time.
+    pub fn log_summary(&self) {
+        let counts = &self.counts;
+        let duration = counts
+            .span
+            .map(|(start, end)| Duration::from_nanos(end - start))
+            .unwrap_or_default();
+        log::debug!(
+            "streamed {} samples on {} cpus over {:?}",
+            counts.samples,
+            counts.cpus.len(),
+            duration
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use zerocopy::LayoutVerified;
+
+    fn event(time: u64, cpu: u32, ip: u64, weight: u64) -> Event {
+        let bytes = vec![0; std::mem::size_of::<Event>()];
+        let mut event = *LayoutVerified::<_, Event>::new_unaligned(&bytes[..]).unwrap();
+        event.time = U64::new(time);
+        event.cpu = U32::new(cpu);
+        event.weight = U64::new(weight);
+        event.key.stack[0] = U64::new(ip);
+        event
+    }
+
+    #[test]
+    fn count_events_by_stack() {
+        let mut counts = Counts::default();
+        counts.add(&[event(30, 0, 0x10, 1), event(10, 1, 0x20, 2)]);
+        counts.add(&[event(20, 1, 0x10, 3)]);
+        assert_eq!(counts.samples, 3);
+        assert_eq!(counts.stacks.len(), 2);
+        let events = |ip| {
+            counts
+                .stacks
+                .iter()
+                .find(|(key, _)| key.stack[0].get() == ip)
+                .map(|(_, events)| events.clone())
+        };
+        assert_eq!(events(0x10), Some(vec![(30, 1), (20, 3)]));
+        assert_eq!(events(0x20), Some(vec![(10, 2)]));
+        assert_eq!(counts.cpus.len(), 2);
+        assert_eq!(counts.span, Some((10, 30)));
+    }
+}
@@ -1,8 +1,10 @@
 use anyhow::Result;
 use gimli::{
-    CfaRule, NativeEndian, Reader, RegisterRule, UninitializedUnwindContext, UnwindSection,
+    CfaRule, Expression, NativeEndian, Operation, Reader, RegisterRule, UninitializedUnwindContext,
+    UnwindSection,
 };
 use object::{Object, ObjectSection};
+use std::collections::BTreeMap;
 
 /// Dwarf instruction.
 #[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
@@ -10,6 +12,7 @@ pub struct Instruction {
     op: Op,
     reg: Option<Reg>,
     offset: Option<i64>,
+    arg: Option<u8>,
 }
 
 impl Instruction {
@@ -18,6 +21,7 @@ impl Instruction {
             op: Op::Unimplemented,
             reg: None,
             offset: None,
+            arg: None,
         }
     }
 
@@ -26,6 +30,7 @@ impl Instruction {
             op: Op::Undefined,
             reg: None,
             offset: None,
+            arg: None,
         }
     }
 
@@ -34,6 +39,7 @@ impl Instruction {
             op: Op::CfaOffset,
             reg: None,
             offset: Some(offset),
+            arg: None,
         }
     }
 
@@ -42,6 +48,16 @@ impl Instruction {
             op: Op::Register,
             reg: Some(reg),
             offset: Some(offset),
+            arg: None,
+        }
+    }
+
+    pub fn plt(offset: i64, threshold: u8) -> Self {
+        Self {
+            op: Op::Plt,
+            reg: Some(Reg::Rsp),
+            offset: Some(offset),
+            arg: Some(threshold),
         }
     }
 
@@ -60,6 +76,12 @@ impl Instruction {
         self.offset
     }
 
+    /// Threshold of a `Plt` instruction.
+    #[inline(always)]
+    pub fn arg(&self) -> Option<u8> {
+        self.arg
+    }
+
     #[inline(always)]
     pub fn is_implemented(&self) -> bool {
         self.op != Op::Unimplemented
@@ -87,6 +109,11 @@ impl std::fmt::Display for Instruction {
                 let op = if offset >= 0 { "+" } else { "" };
                 write!(f, "{}{}{}", reg, op, offset)
             }
+            Op::Plt => {
+                let offset = self.offset.unwrap();
+                let op = if offset >= 0 { "+" } else { "" };
+                write!(f, "plt(rsp{}{},{})", op, offset, self.arg.unwrap())
+            }
         }
     }
 }
@@ -104,6 +131,9 @@ pub enum Op {
     CfaOffset = 2,
     /// Value of a machine register plus offset.
     Register = 3,
+    /// `cfa` of a PLT entry, `rsp` plus offset plus 8 when `rip & 15` is at least the
+    /// threshold, after the entry pushed the index of its relocation.
+    Plt = 4,
 }
 
 /// Dwarf register.
@@ -112,6 +142,7 @@ pub enum Op {
 pub enum Reg {
     Rip = libc::REG_RIP as u8,
     Rsp = libc::REG_RSP as u8,
+    Rbp = libc::REG_RBP as u8,
 }
 
 impl Reg {
@@ -119,6 +150,7 @@ impl Reg {
         Some(match reg {
             gimli::X86_64::RA => Self::Rip,
             gimli::X86_64::RSP => Self::Rsp,
+            gimli::X86_64::RBP => Self::Rbp,
             _ => return None,
         })
     }
@@ -129,6 +161,7 @@ impl std::fmt::Display for Reg {
         match self {
             Self::Rip => write!(f, "rip"),
             Self::Rsp => write!(f, "rsp"),
+            Self::Rbp => write!(f, "rbp"),
         }
     }
 }
@@ -144,12 +177,15 @@ pub struct UnwindTableRow {
     pub rip: Instruction,
     /// Instruction to unwind `rsp` register.
     pub rsp: Instruction,
+    /// Instruction to unwind `rbp` register, `rbp+0` when it keeps its value. Functions
+    /// with a frame pointer or dynamic stack allocations compute the `cfa` from `rbp`.
+    pub rbp: Instruction,
 }
 
 impl UnwindTableRow {
     pub fn parse<R: Eq + Reader>(
         row: &gimli::UnwindTableRow<R>,
-        _encoding: gimli::Encoding,
+        encoding: gimli::Encoding,
     ) -> Result<Self> {
         Ok(Self {
             start_address: row.start_address() as _,
@@ -171,8 +207,23 @@ impl UnwindTableRow {
                         Instruction::unimpl()
                     }
                 }
+                CfaRule::Expression(expr) => {
+                    if let Some(ins) = plt_expression(expr.clone(), encoding) {
+                        ins
+                    } else {
+                        log::debug!("unimpl cfa {:?}", row.cfa());
+                        Instruction::unimpl()
+                    }
+                }
+            },
+            rbp: match row.register(gimli::X86_64::RBP) {
+                // callee saved registers without a rule keep their value.
+                RegisterRule::Undefined | RegisterRule::SameValue => {
+                    Instruction::reg_offset(Reg::Rbp, 0)
+                }
+                RegisterRule::Offset(offset) => Instruction::cfa_offset(offset),
                 _ => {
-                    log::debug!("unimpl cfa {:?}", row.cfa());
+                    log::debug!("unimpl rbp {:?}", row.register(gimli::X86_64::RBP));
                     Instruction::unimpl()
                 }
             },
@@ -180,15 +231,64 @@ impl UnwindTableRow {
     }
 }
 
+/// Lowers the cfa expression the linker emits for the entries of a `.plt` section,
+/// `rsp+offset + (((rip & 15) >= threshold) << 3)`.
+fn plt_expression<R: Reader>(
+    expr: Expression<R>,
+    encoding: gimli::Encoding,
+) -> Option<Instruction> {
+    use Operation::*;
+    let ops = operations(expr, encoding).ok()?;
+    let (base, ip, mask, threshold, shift) = match ops.as_slice() {
+        [base, ip, mask, And, threshold, Ge, shift, Shl, Plus] => {
+            (base, ip, mask, threshold, shift)
+        }
+        _ => return None,
+    };
+    match (base, ip, mask, threshold, shift) {
+        (
+            RegisterOffset {
+                register: rsp,
+                offset,
+                ..
+            },
+            RegisterOffset {
+                register: rip,
+                offset: 0,
+                ..
+            },
+            UnsignedConstant { value: 15 },
+            UnsignedConstant { value: threshold },
+            UnsignedConstant { value: 3 },
+        ) if *rsp == gimli::X86_64::RSP && *rip == gimli::X86_64::RA && *threshold < 16 => {
+            Some(Instruction::plt(*offset, *threshold as u8))
+        }
+        _ => None,
+    }
+}
+
+fn operations<R: Reader>(
+    expr: Expression<R>,
+    encoding: gimli::Encoding,
+) -> Result<Vec<Operation<R>>> {
+    let mut ops = vec![];
+    let mut iter = expr.operations(encoding);
+    while let Some(op) = iter.next()? {
+        ops.push(op);
+    }
+    Ok(ops)
+}
+
 impl std::fmt::Display for UnwindTableRow {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         write!(
             f,
-            "0x{:0>6x}-0x{:0>6x} {:8} {:8}",
+            "0x{:0>6x}-0x{:0>6x} {:8} {:8} {:8}",
             self.start_address,
             self.end_address,
             self.rip.to_string(),
             self.rsp.to_string(),
+            self.rbp.to_string(),
         )
     }
 }
@@ -197,6 +297,9 @@ impl std::fmt::Display for UnwindTableRow {
 #[derive(Clone, Debug, Eq, Hash, PartialEq)]
 pub struct UnwindTable {
     pub rows: Vec<UnwindTableRow>,
+    /// Cfa expressions that couldn't be lowered or decoded and the number of rows using
+    /// them.
+    pub unsupported: BTreeMap<String, usize>,
 }
 
 impl UnwindTable {
@@ -223,6 +326,7 @@ impl UnwindTable {
         let mut ctx = UninitializedUnwindContext::new();
         let mut entries = eh_frame.entries(&bases);
         let mut rows = vec![];
+        let mut unsupported = BTreeMap::<_, usize>::new();
         while let Some(entry) = entries.next()? {
             match entry {
                 gimli::CieOrFde::Cie(_) => {}
@@ -231,22 +335,111 @@ impl UnwindTable {
                     let encoding = fde.cie().encoding();
                     let mut table = fde.rows(&eh_frame, &bases, &mut ctx)?;
                     while let Some(row) = table.next_row()? {
-                        rows.push(UnwindTableRow::parse(row, encoding)?);
+                        let parsed = UnwindTableRow::parse(row, encoding)?;
+                        if let CfaRule::Expression(expr) = row.cfa() {
+                            if !parsed.rsp.is_implemented() {
+                                let expr = match operations(*expr, encoding) {
+                                    Ok(ops) => format!("{:?}", ops),
+                                    Err(err) => format!("undecodable ({})", err),
+                                };
+                                *unsupported.entry(expr).or_default() += 1;
+                            }
+                        }
+                        rows.push(parsed);
                     }
                 }
             }
         }
         rows.sort_unstable_by_key(|row| row.start_address);
-        Ok(Self { rows })
+        Ok(Self { rows, unsupported })
     }
 }
 
 impl std::fmt::Display for UnwindTable {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        writeln!(f, "{:18} {:8} {:8}", "ip", "rip", "rsp",)?;
+        writeln!(f, "{:18} {:8} {:8} {:8}", "ip", "rip", "rsp", "rbp")?;
         for row in &self.rows {
             writeln!(f, "{}", row)?;
         }
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use gimli::{EndianSlice, Format, LittleEndian};
+
+    fn expression(bytes: &[u8]) -> Option<Instruction> {
+        let encoding = gimli::Encoding {
+            format: Format::Dwarf32,
+            version: 1,
+            address_size: 8,
+        };
+        plt_expression(Expression(EndianSlice::new(bytes, LittleEndian)), encoding)
+    }
+
+    #[test]
+    fn lower_plt_expressions() {
+        // DW_OP_breg7 8, DW_OP_breg16 0, DW_OP_lit15, DW_OP_and, DW_OP_lit11, DW_OP_ge,
+        // DW_OP_lit3, DW_OP_shl, DW_OP_plus
+        let plt = [
+            0x77, 0x08, 0x80, 0x00, 0x3f, 0x1a, 0x3b, 0x2a, 0x33, 0x24, 0x22,
+        ];
+        let ins = expression(&plt).unwrap();
+        assert_eq!(ins, Instruction::plt(8, 11));
+        assert_eq!(ins.to_string(), "plt(rsp+8,11)");
+        // the entries of `.plt` with ibt push the index after 10 bytes.
+        let ibt = [
+            0x77, 0x08, 0x80, 0x00, 0x3f, 0x1a, 0x3a, 0x2a, 0x33, 0x24, 0x22,
+        ];
+        assert_eq!(expression(&ibt), Some(Instruction::plt(8, 10)));
+        // DW_OP_breg7 160, DW_OP_deref of a signal frame.
+        assert_eq!(expression(&[0x77, 0xa0, 0x01, 0x06]), None);
+    }
+
+    #[test]
+    fn parse_rbp_based_cfa() {
+        #[rustfmt::skip]
+        let eh_frame = [
+            // cie: version 1, no augmentation, code alignment 1, data alignment -8,
+            // return address in rip.
+            0x10, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00, 0x01, 0x78, 0x10,
+            // DW_CFA_def_cfa rsp 8, DW_CFA_offset rip 1, DW_CFA_nop, DW_CFA_nop
+            0x0c, 0x07, 0x08, 0x90, 0x01, 0x00, 0x00,
+            // fde of 0x1000-0x1020 with the cie at offset 0.
+            0x1c, 0x00, 0x00, 0x00, 0x18, 0x00, 0x00, 0x00,
+            0x00, 0x10, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x20, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            // push rbp: DW_CFA_advance_loc 1, DW_CFA_def_cfa_offset 16, DW_CFA_offset rbp 2
+            0x41, 0x0e, 0x10, 0x86, 0x02,
+            // mov rbp, rsp: DW_CFA_advance_loc 3, DW_CFA_def_cfa_register rbp
+            0x43, 0x0d, 0x06,
+        ];
+        let mut eh_frame = gimli::EhFrame::new(&eh_frame, LittleEndian);
+        eh_frame.set_address_size(8);
+        let bases = gimli::BaseAddresses::default();
+        let mut ctx = UninitializedUnwindContext::new();
+        let mut rows = vec![];
+        let mut entries = eh_frame.entries(&bases);
+        while let Some(entry) = entries.next().unwrap() {
+            if let gimli::CieOrFde::Fde(partial) = entry {
+                let fde = partial
+                    .parse(|_, bases, o| eh_frame.cie_from_offset(bases, o))
+                    .unwrap();
+                let mut table = fde.rows(&eh_frame, &bases, &mut ctx).unwrap();
+                while let Some(row) = table.next_row().unwrap() {
+                    let encoding = fde.cie().encoding();
+                    rows.push(UnwindTableRow::parse(row, encoding).unwrap());
+                }
+            }
+        }
+        assert_eq!(rows.len(), 3);
+        assert_eq!(rows[0].rbp, Instruction::reg_offset(Reg::Rbp, 0));
+        assert_eq!(rows[1].rbp, Instruction::cfa_offset(-16));
+        assert_eq!(rows[2].start_address, 0x1004);
+        assert_eq!(rows[2].rip, Instruction::cfa_offset(-8));
+        assert_eq!(rows[2].rsp, Instruction::reg_offset(Reg::Rbp, 16));
+        assert_eq!(rows[2].rbp, Instruction::cfa_offset(-16));
+    }
+}